@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE bpp_groups DROP CONSTRAINT bpp_groups_group_name_key;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- Groups which already share a name keep it only for the oldest one, the others get their id appended
+UPDATE bpp_groups SET group_name = group_name || ' (' || group_id || ')'
+WHERE group_id NOT IN (SELECT MIN(group_id) FROM bpp_groups GROUP BY group_name);
+ALTER TABLE bpp_groups ADD CONSTRAINT bpp_groups_group_name_key UNIQUE (group_name);
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE bpp_ranks DROP COLUMN payout_multiplier CASCADE;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE bpp_ranks ADD COLUMN payout_multiplier DOUBLE PRECISION NOT NULL DEFAULT 1.0;
\ No newline at end of file
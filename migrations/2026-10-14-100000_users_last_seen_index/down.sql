@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX bpp_users_last_seen_at_idx;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+CREATE INDEX bpp_users_last_seen_at_idx ON bpp_users (last_seen_at);
\ No newline at end of file
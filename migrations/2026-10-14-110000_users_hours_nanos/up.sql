@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE bpp_users ADD COLUMN hours_nanos INTEGER NOT NULL DEFAULT 0;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE bpp_users ADD COLUMN deleted_at TIMESTAMP;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE bpp_ingest_position;
\ No newline at end of file
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+CREATE TABLE bpp_ingest_position (
+    id INT PRIMARY KEY DEFAULT 1 CHECK (id = 1),
+    last_message_id VARCHAR NOT NULL,
+    last_published_at TIMESTAMP NOT NULL
+);
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX bpp_users_permissions_expires_at_idx;
+ALTER TABLE bpp_users_permissions DROP COLUMN expires_at;
\ No newline at end of file
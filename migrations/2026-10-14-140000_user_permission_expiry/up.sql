@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE bpp_users_permissions ADD COLUMN expires_at TIMESTAMP;
+CREATE INDEX bpp_users_permissions_expires_at_idx ON bpp_users_permissions (expires_at) WHERE expires_at IS NOT NULL;
\ No newline at end of file
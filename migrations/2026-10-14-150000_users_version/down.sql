@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE bpp_users DROP COLUMN version;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE bpp_users ADD COLUMN version BIGINT NOT NULL DEFAULT 1;
\ No newline at end of file
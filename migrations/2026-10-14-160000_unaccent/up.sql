@@ -0,0 +1,2 @@
+-- Your SQL goes here
+CREATE EXTENSION IF NOT EXISTS unaccent;
\ No newline at end of file
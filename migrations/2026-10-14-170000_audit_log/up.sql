@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE bpp_audit_log (
+    audit_id SERIAL PRIMARY KEY,
+    actor VARCHAR NOT NULL,
+    operation VARCHAR NOT NULL,
+    target_id VARCHAR,
+    affected_count BIGINT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
\ No newline at end of file
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX bpp_audit_log_created_at_idx;
+DROP INDEX bpp_audit_log_target_idx;
+ALTER TABLE bpp_audit_log DROP COLUMN after_snapshot;
+ALTER TABLE bpp_audit_log DROP COLUMN before_snapshot;
\ No newline at end of file
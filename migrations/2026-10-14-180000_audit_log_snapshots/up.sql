@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE bpp_audit_log ADD COLUMN before_snapshot TEXT;
+ALTER TABLE bpp_audit_log ADD COLUMN after_snapshot TEXT;
+CREATE INDEX bpp_audit_log_target_idx ON bpp_audit_log (target_id, created_at);
+CREATE INDEX bpp_audit_log_created_at_idx ON bpp_audit_log (created_at);
\ No newline at end of file
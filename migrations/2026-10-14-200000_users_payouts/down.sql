@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE bpp_users DROP COLUMN unpaid_millis;
+ALTER TABLE bpp_users DROP COLUMN last_paid_at;
\ No newline at end of file
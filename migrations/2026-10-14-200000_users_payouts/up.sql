@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE bpp_users ADD COLUMN last_paid_at TIMESTAMP;
+ALTER TABLE bpp_users ADD COLUMN unpaid_millis BIGINT NOT NULL DEFAULT 0;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE bpp_users_previous_names;
\ No newline at end of file
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+CREATE TABLE bpp_users_previous_names (
+    channel_id VARCHAR NOT NULL REFERENCES bpp_users(channel_id),
+    display_name VARCHAR NOT NULL,
+    replaced_at TIMESTAMP NOT NULL,
+    PRIMARY KEY(channel_id, display_name)
+);
\ No newline at end of file
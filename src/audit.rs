@@ -0,0 +1,117 @@
+use chrono::Utc;
+use diesel::prelude::*;
+use serde_json::{json, Value};
+use tonic::Request;
+
+use crate::auth::caller_of;
+use crate::models::{Group, GroupPermission, InsertAuditEntry, Rank, User};
+
+/// Recorded as the actor of changes whose caller wasn't identified
+pub const UNKNOWN_ACTOR: &str = "unknown";
+
+/// Returns who a change is recorded for, which is the caller if the request identifies them
+pub fn actor_of<T>(request: &Request<T>) -> String {
+    caller_of(request).unwrap_or(UNKNOWN_ACTOR).to_string()
+}
+
+/// A change made through the API, to be recorded in the audit log
+pub struct AuditChange<'a> {
+    actor: &'a str,
+    operation: &'a str,
+    target_id: Option<String>,
+    affected_count: i64,
+    before: Option<Value>,
+    after: Option<Value>,
+}
+
+impl<'a> AuditChange<'a> {
+    /// Starts a change of a single thing, the operation is named after the RPC
+    pub fn new(actor: &'a str, operation: &'a str) -> AuditChange<'a> {
+        AuditChange {
+            actor,
+            operation,
+            target_id: None,
+            affected_count: 1,
+            before: None,
+            after: None,
+        }
+    }
+
+    pub fn target<T: ToString>(mut self, target_id: T) -> Self {
+        self.target_id = Some(target_id.to_string());
+        self
+    }
+
+    pub fn affected(mut self, affected_count: i64) -> Self {
+        self.affected_count = affected_count;
+        self
+    }
+
+    pub fn before(mut self, before: Value) -> Self {
+        self.before = Some(before);
+        self
+    }
+
+    pub fn after(mut self, after: Value) -> Self {
+        self.after = Some(after);
+        self
+    }
+
+    /// Records the change, inside a transaction the change is only kept if this succeeds
+    pub fn save(&self, conn: &PgConnection) -> QueryResult<usize> {
+        InsertAuditEntry {
+            actor: self.actor,
+            operation: self.operation,
+            target_id: self.target_id.as_deref(),
+            affected_count: self.affected_count,
+            created_at: Utc::now().naive_utc(),
+            before_snapshot: self.before.as_ref().map(Value::to_string),
+            after_snapshot: self.after.as_ref().map(Value::to_string),
+        }
+        .save_to_database(conn)
+    }
+}
+
+pub fn user_snapshot(user: &User) -> Value {
+    json!({
+        "channel_id": user.channel_id,
+        "display_name": user.display_name,
+        "hours_seconds": user.hours_seconds,
+        "hours_nanos": user.hours_nanos,
+        "money": user.money,
+        "first_seen_at": user.first_seen_at.to_string(),
+        "last_seen_at": user.last_seen_at.to_string(),
+        "deleted_at": user.deleted_at.map(|deleted_at| deleted_at.to_string()),
+        "version": user.version,
+        "message_count": user.message_count,
+    })
+}
+
+pub fn group_snapshot(group: &Group) -> Value {
+    json!({
+        "group_id": group.group_id,
+        "group_name": group.group_name,
+        "bonus_payout": group.bonus_payout,
+        "group_sorting": group.group_sorting,
+    })
+}
+
+/// A group together with the permissions it grants
+pub fn group_with_permissions_snapshot(group: &Group, permissions: &[GroupPermission]) -> Value {
+    let permissions: Vec<Value> = permissions
+        .iter()
+        .map(|p| json!({ "permission": p.permission, "granted": p.granted }))
+        .collect();
+    json!({ "group": group_snapshot(group), "permissions": permissions })
+}
+
+pub fn rank_snapshot(rank: &Rank) -> Value {
+    json!({
+        "rank_id": rank.rank_id,
+        "rank_name": rank.rank_name,
+        "rank_sorting": rank.rank_sorting,
+        "hour_requirement_seconds": rank.hour_requirement_seconds,
+        "hour_requirement_nanos": rank.hour_requirement_nanos,
+        "payout_multiplier": rank.payout_multiplier,
+    })
+}
@@ -0,0 +1,139 @@
+use std::sync::Arc;
+
+use diesel::PgConnection;
+use log::error;
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+use crate::permissions::resolve_user_permission;
+
+/// Rejects requests which don't carry the shared service token as a bearer token
+#[derive(Clone)]
+pub struct TokenAuth {
+    token: Option<Arc<str>>,
+}
+
+impl TokenAuth {
+    /// Creates the interceptor, which lets every request through if no token is given
+    pub fn new(token: Option<String>) -> TokenAuth {
+        TokenAuth {
+            token: token.map(Arc::from),
+        }
+    }
+}
+
+/// Compares two strings in a time that doesn't depend on where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
+
+impl Interceptor for TokenAuth {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let expected = match &self.token {
+            Some(token) => token,
+            None => return Ok(request),
+        };
+
+        let provided = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match provided {
+            Some(provided) if constant_time_eq(provided.as_bytes(), expected.as_bytes()) => Ok(request),
+            _ => Err(Status::unauthenticated("A valid service token is required")),
+        }
+    }
+}
+
+/// The metadata key which identifies the user on whose behalf a request is made
+pub const CALLER_METADATA_KEY: &str = "x-caller-channel-id";
+
+/// Returns the user on whose behalf a request is made, if the caller identified them
+pub fn caller_of<T>(request: &Request<T>) -> Option<&str> {
+    request
+        .metadata()
+        .get(CALLER_METADATA_KEY)
+        .and_then(|value| value.to_str().ok())
+        .filter(|caller| !caller.is_empty())
+}
+
+/// The permission callers need for each mutating RPC, and for reads of sensitive data
+const RPC_PERMISSIONS: &[(&str, &str)] = &[
+    ("update_user", "bpp.users.update"),
+    ("update_users", "bpp.users.update"),
+    ("delete_user", "bpp.users.delete"),
+    ("delete_users", "bpp.users.delete"),
+    ("hard_delete_user", "bpp.users.erase"),
+    ("create_user", "bpp.users.create"),
+    ("export_users", "bpp.users.export"),
+    ("import_users", "bpp.users.import"),
+    ("merge_users", "bpp.users.merge"),
+    ("adjust_money", "bpp.money.adjust"),
+    ("transfer_money", "bpp.money.transfer"),
+    ("reset_all_money", "bpp.money.reset"),
+    ("reset_all_hours", "bpp.hours.reset"),
+    ("update_group", "bpp.groups.update"),
+    ("update_groups", "bpp.groups.update"),
+    ("rename_group", "bpp.groups.update"),
+    ("delete_group", "bpp.groups.delete"),
+    ("delete_groups", "bpp.groups.delete"),
+    ("create_group", "bpp.groups.create"),
+    ("add_user_to_group", "bpp.groups.members"),
+    ("remove_user_from_group", "bpp.groups.members"),
+    ("update_rank", "bpp.ranks.update"),
+    ("update_ranks", "bpp.ranks.update"),
+    ("delete_rank", "bpp.ranks.delete"),
+    ("delete_ranks", "bpp.ranks.delete"),
+    ("create_rank", "bpp.ranks.create"),
+    ("user_grant_permission", "bpp.permissions.grant"),
+    ("user_revoke_permisison", "bpp.permissions.revoke"),
+    ("group_grant_permission", "bpp.permissions.grant"),
+    ("group_revoke_permission", "bpp.permissions.revoke"),
+    ("get_audit_log", "bpp.audit.read"),
+];
+
+/// Returns every permission the RPCs require, some more than once
+pub fn rpc_permissions() -> impl Iterator<Item = &'static str> {
+    RPC_PERMISSIONS.iter().map(|(_, permission)| *permission)
+}
+
+/// Returns the permission a caller needs for an RPC, if it needs one
+pub fn required_permission(method: &str) -> Option<&'static str> {
+    RPC_PERMISSIONS
+        .iter()
+        .find(|(rpc, _)| *rpc == method)
+        .map(|(_, permission)| *permission)
+}
+
+/// Checks that the caller of a request holds the permission the RPC requires
+pub fn authorize_caller<T>(method: &str, request: &Request<T>, conn: &PgConnection) -> Result<(), Status> {
+    let permission = match required_permission(method) {
+        Some(permission) => permission,
+        None => return Ok(()),
+    };
+    let caller = match caller_of(request) {
+        Some(caller) => caller,
+        None => {
+            return Err(Status::permission_denied(format!(
+                "{} requires the caller to be identified with {}",
+                method, CALLER_METADATA_KEY
+            )))
+        }
+    };
+
+    let has_permission = resolve_user_permission(caller, permission, false, conn).map_err(|e| {
+        error!("{}", e);
+        Status::internal("Failed to check the permissions of the caller")
+    })?;
+    if !has_permission {
+        return Err(Status::permission_denied(format!(
+            "{} requires the permission {}",
+            method, permission
+        )));
+    }
+    Ok(())
+}
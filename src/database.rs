@@ -0,0 +1,38 @@
+use diesel::connection::SimpleConnection;
+use diesel::r2d2::{CustomizeConnection, Error};
+use diesel::PgConnection;
+
+/// Session settings applied to every connection the pool opens
+#[derive(Debug)]
+pub struct ConnectionSettings {
+    /// After how many milliseconds Postgres cancels a statement, 0 lets statements run forever
+    pub statement_timeout_ms: u32,
+    /// Shown for the connections in `pg_stat_activity`
+    pub application_name: String,
+}
+
+/// Names the connections of this service, including the instance if it's known, so replicas can
+/// be told apart
+pub fn application_name(instance: Option<&str>) -> String {
+    match instance.map(str::trim).filter(|instance| !instance.is_empty()) {
+        Some(instance) => format!("userservice@{}", instance),
+        None => "userservice".to_string(),
+    }
+}
+
+/// Quotes a string as a SQL literal
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+impl CustomizeConnection<PgConnection, Error> for ConnectionSettings {
+    fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), Error> {
+        // A cancelled statement fails its query, so the connection is free again right after
+        conn.batch_execute(&format!(
+            "SET statement_timeout = {}; SET application_name = {}",
+            self.statement_timeout_ms,
+            quote_literal(&self.application_name)
+        ))
+        .map_err(Error::QueryError)
+    }
+}
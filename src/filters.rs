@@ -0,0 +1,559 @@
+use chrono::NaiveDateTime;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::dsl::sql;
+use diesel::sql_types::{Bool, Text};
+use serde::{Deserialize, Serialize};
+
+use crate::models::{Rank, User};
+use crate::schema::{bpp_groups, bpp_groups_users, bpp_users};
+use crate::userservice::bpp_user_filter::Filter;
+use crate::userservice::bpp_user_filters::{FilterCombinator, SortingFields};
+use crate::userservice::sort_key::{Direction, Field};
+use crate::userservice::{BppUserFilters, ComparisonOperator, SortKey};
+
+sql_function!(fn unaccent(text: Text) -> Text);
+sql_function!(fn lower(text: Text) -> Text);
+
+pub type UserFilterExpression<'a> = Box<dyn BoxableExpression<bpp_users::table, Pg, SqlType = Bool> + 'a>;
+
+/// Compares a column against the operands of a range filter
+///
+/// `Between` includes both the lower and the upper value.
+macro_rules! range_expression {
+    ($column:expr, $range:expr) => {
+        range_expression!($column, $range.operator(), $range.value, $range.upper_value)
+    };
+    ($column:expr, $operator:expr, $value:expr, $upper_value:expr) => {
+        match $operator {
+            ComparisonOperator::Equal => Box::new($column.eq($value)),
+            ComparisonOperator::GreaterThan => Box::new($column.gt($value)),
+            ComparisonOperator::GreaterThanOrEqual => Box::new($column.ge($value)),
+            ComparisonOperator::LessThan => Box::new($column.lt($value)),
+            ComparisonOperator::LessThanOrEqual => Box::new($column.le($value)),
+            ComparisonOperator::Between => Box::new($column.between($value, $upper_value)),
+        }
+    };
+}
+
+/// Converts a timestamp of a filter, a missing or invalid one counts as the unix epoch
+fn filter_timestamp(timestamp: &Option<prost_types::Timestamp>) -> NaiveDateTime {
+    timestamp
+        .as_ref()
+        .and_then(|t| NaiveDateTime::from_timestamp_opt(t.seconds, t.nanos as u32))
+        .unwrap_or_else(|| NaiveDateTime::from_timestamp(0, 0))
+}
+
+/// Escapes the wildcard characters of a LIKE pattern, so the input only matches literally
+fn escape_like(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Hours as seconds and nanos, which compare like the duration they make up
+type Hours = (i64, i32);
+
+/// Returns the hours of the users holding a rank, as an inclusive lower and
+/// an exclusive upper bound
+///
+/// Users hold the rank with the highest sorting among those whose requirement they reached, so the
+/// range ends at the lowest requirement of the ranks sorted above. Users below every rank have the
+/// "default" rank. Returns None if no rank has the name.
+fn rank_hours_range(name: &str, ranks: &[Rank]) -> Option<(Option<Hours>, Option<Hours>)> {
+    let requirements = ranks.iter().map(|rank| (rank.rank_sorting, rank.hour_requirement()));
+
+    match ranks.iter().find(|rank| rank.rank_name == name) {
+        Some(selected) => {
+            let upper = requirements
+                .filter(|(sorting, _)| *sorting > selected.rank_sorting)
+                .map(|(_, requirement)| requirement)
+                .min();
+            Some((Some(selected.hour_requirement()), upper))
+        }
+        None if name == "default" => Some((None, requirements.map(|(_, requirement)| requirement).min())),
+        None => None,
+    }
+}
+
+/// Matches users with at least the given hours, the nanos only decide between equal seconds
+fn hours_at_least<'a>((seconds, nanos): Hours) -> UserFilterExpression<'a> {
+    use crate::schema::bpp_users::dsl::*;
+    Box::new(hours_seconds.gt(seconds).or(hours_seconds.eq(seconds).and(hours_nanos.ge(nanos))))
+}
+
+/// Matches users with less than the given hours, the nanos only decide between equal seconds
+fn hours_below<'a>((seconds, nanos): Hours) -> UserFilterExpression<'a> {
+    use crate::schema::bpp_users::dsl::*;
+    Box::new(hours_seconds.lt(seconds).or(hours_seconds.eq(seconds).and(hours_nanos.lt(nanos))))
+}
+
+/// The supported filters, named like the fields of the filter oneof in the proto
+///
+/// Has to list every variant filter_expression matches on.
+pub const FILTER_NAMES: &[&str] = &[
+    "channel_id",
+    "name",
+    "hours",
+    "money",
+    "name_contains",
+    "hours_range",
+    "money_range",
+    "last_seen_range",
+    "group_id",
+    "group_name",
+    "rank",
+];
+
+/// Turns a single filter into a boolean SQL expression on the users table
+fn filter_expression<'a>(filter: &'a Filter, ranks: &[Rank]) -> UserFilterExpression<'a> {
+    use crate::schema::bpp_users::dsl::*;
+
+    match filter {
+        Filter::ChannelId(filter_channel_id) => Box::new(channel_id.eq(filter_channel_id)),
+        // Names are compared without case and accents, so "jose" finds "José"
+        Filter::Name(filter_name) => {
+            Box::new(lower(unaccent(display_name)).eq(lower(unaccent(filter_name))))
+        }
+        Filter::NameContains(filter_name) => {
+            let pattern = format!("%{}%", escape_like(filter_name));
+            Box::new(unaccent(display_name).ilike(unaccent(pattern)))
+        }
+        Filter::Hours(filter_hours) => Box::new(hours_seconds.eq(filter_hours)),
+        Filter::Money(filter_money) => Box::new(money.eq(filter_money)),
+        Filter::HoursRange(range) => range_expression!(hours_seconds, range),
+        Filter::MoneyRange(range) => range_expression!(money, range),
+        Filter::LastSeenRange(range) => range_expression!(
+            last_seen_at,
+            range.operator(),
+            filter_timestamp(&range.value),
+            filter_timestamp(&range.upper_value)
+        ),
+        Filter::GroupId(filter_group_id) => Box::new(
+            channel_id.eq_any(
+                bpp_groups_users::table
+                    .filter(bpp_groups_users::group_id.eq(filter_group_id))
+                    .select(bpp_groups_users::channel_id),
+            ),
+        ),
+        Filter::GroupName(filter_group_name) => Box::new(
+            channel_id.eq_any(
+                bpp_groups_users::table
+                    .inner_join(bpp_groups::table)
+                    .filter(bpp_groups::group_name.eq(filter_group_name))
+                    .select(bpp_groups_users::channel_id),
+            ),
+        ),
+        Filter::Rank(filter_rank) => match rank_hours_range(filter_rank, ranks) {
+            Some((Some(lower), Some(upper))) => Box::new(hours_at_least(lower).and(hours_below(upper))),
+            Some((Some(lower), None)) => hours_at_least(lower),
+            Some((None, Some(upper))) => hours_below(upper),
+            Some((None, None)) => Box::new(sql::<Bool>("TRUE")),
+            None => Box::new(sql::<Bool>("FALSE")),
+        },
+    }
+}
+
+/// Builds a query for all users matching the filters of a request
+///
+/// The filters are combined with AND, unless the request asks for OR. Deleted users are left out,
+/// unless the request includes them. Rank filters are resolved against the given ranks.
+pub fn filter_users_query<'a>(
+    filter_request: &'a BppUserFilters,
+    ranks: &[Rank],
+) -> bpp_users::BoxedQuery<'a, Pg> {
+    let mut query = bpp_users::table.into_boxed();
+    if !filter_request.include_deleted {
+        query = query.filter(bpp_users::deleted_at.is_null());
+    }
+
+    let combinator = filter_request.combinator();
+    let mut expressions = filter_request
+        .filters
+        .iter()
+        .filter_map(|filter| filter.filter.as_ref())
+        .map(|filter| filter_expression(filter, ranks));
+    if let Some(first) = expressions.next() {
+        let combined = expressions.fold(first, |combined, expression| match combinator {
+            FilterCombinator::And => Box::new(combined.and(expression)),
+            FilterCombinator::Or => Box::new(combined.or(expression)),
+        });
+        query = query.filter(combined);
+    }
+
+    query
+}
+
+/// Orders users by sort keys, the earlier keys take precedence
+pub fn sort_users_query<'a>(
+    mut query: bpp_users::BoxedQuery<'a, Pg>,
+    sort_keys: &[SortKey],
+) -> bpp_users::BoxedQuery<'a, Pg> {
+    use crate::schema::bpp_users::dsl::*;
+
+    macro_rules! then_order_by {
+        ($column:expr, $direction:expr) => {
+            match $direction {
+                Direction::Asc => query.then_order_by($column.asc()),
+                Direction::Desc => query.then_order_by($column.desc()),
+            }
+        };
+    }
+
+    for sort_key in sort_keys {
+        let direction = sort_key.direction();
+        query = match sort_key.field() {
+            // The nanos only matter between users with the same seconds
+            Field::Hours => match direction {
+                Direction::Asc => query.then_order_by((hours_seconds.asc(), hours_nanos.asc())),
+                Direction::Desc => query.then_order_by((hours_seconds.desc(), hours_nanos.desc())),
+            },
+            Field::Money => then_order_by!(money, direction),
+            Field::DisplayName => then_order_by!(display_name, direction),
+            Field::FirstSeenAt => then_order_by!(first_seen_at, direction),
+            Field::LastSeenAt => then_order_by!(last_seen_at, direction),
+            Field::ChannelId => then_order_by!(channel_id, direction),
+            Field::MessageCount => then_order_by!(message_count, direction),
+        };
+    }
+    query
+}
+
+/// Names a sort field like its value in the proto
+fn sort_field_name(field: Field) -> &'static str {
+    match field {
+        Field::Hours => "HOURS",
+        Field::Money => "MONEY",
+        Field::DisplayName => "DISPLAY_NAME",
+        Field::FirstSeenAt => "FIRST_SEEN_AT",
+        Field::LastSeenAt => "LAST_SEEN_AT",
+        Field::ChannelId => "CHANNEL_ID",
+        Field::MessageCount => "MESSAGE_COUNT",
+    }
+}
+
+/// Returns the names of all fields users can be sorted by, in the order of their values
+pub fn sort_field_names() -> Vec<&'static str> {
+    // The values of the fields have no gaps, so the first unknown one is past the last field
+    (0..)
+        .map(Field::from_i32)
+        .take_while(Option::is_some)
+        .flatten()
+        .map(sort_field_name)
+        .collect()
+}
+
+/// Returns the sort keys of a request, the legacy sorting is used if it has none
+pub fn request_sort_keys(filter_request: &BppUserFilters) -> Vec<SortKey> {
+    if !filter_request.sort_keys.is_empty() {
+        return filter_request.sort_keys.clone();
+    }
+
+    let (field, direction) = match filter_request.sorting() {
+        SortingFields::HoursAsc => (Field::Hours, Direction::Asc),
+        SortingFields::HoursDesc => (Field::Hours, Direction::Desc),
+        SortingFields::MoneyAsc => (Field::Money, Direction::Asc),
+        SortingFields::MoneyDesc => (Field::Money, Direction::Desc),
+        SortingFields::Default => return Vec::new(),
+    };
+    vec![SortKey {
+        field: field as i32,
+        direction: direction as i32,
+    }]
+}
+
+/// The position of the last user of a page, the next page continues after it
+///
+/// It holds the values of the sort keys and the channel id, which breaks the ties between them.
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    values: Vec<CursorValue>,
+    channel_id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+enum CursorValue {
+    Hours(i64, i32),
+    Money(f64),
+    DisplayName(String),
+    FirstSeenAt(i64, u32),
+    LastSeenAt(i64, u32),
+    ChannelId(String),
+    MessageCount(i64),
+}
+
+impl CursorValue {
+    fn of(user: &User, field: Field) -> CursorValue {
+        match field {
+            Field::Hours => CursorValue::Hours(user.hours_seconds, user.hours_nanos),
+            Field::Money => CursorValue::Money(user.money),
+            Field::DisplayName => CursorValue::DisplayName(user.display_name.clone()),
+            Field::FirstSeenAt => {
+                CursorValue::FirstSeenAt(user.first_seen_at.timestamp(), user.first_seen_at.timestamp_subsec_nanos())
+            }
+            Field::LastSeenAt => {
+                CursorValue::LastSeenAt(user.last_seen_at.timestamp(), user.last_seen_at.timestamp_subsec_nanos())
+            }
+            Field::ChannelId => CursorValue::ChannelId(user.channel_id.clone()),
+            Field::MessageCount => CursorValue::MessageCount(user.message_count),
+        }
+    }
+
+    fn field(&self) -> Field {
+        match self {
+            CursorValue::Hours(..) => Field::Hours,
+            CursorValue::Money(_) => Field::Money,
+            CursorValue::DisplayName(_) => Field::DisplayName,
+            CursorValue::FirstSeenAt(..) => Field::FirstSeenAt,
+            CursorValue::LastSeenAt(..) => Field::LastSeenAt,
+            CursorValue::ChannelId(_) => Field::ChannelId,
+            CursorValue::MessageCount(_) => Field::MessageCount,
+        }
+    }
+
+    /// Returns the columns of the value, each with an expression for users having the same value
+    /// and one for users sorted after it
+    fn expressions(self, direction: Direction) -> Vec<(UserFilterExpression<'static>, UserFilterExpression<'static>)> {
+        use crate::schema::bpp_users::dsl::*;
+
+        macro_rules! key_expressions {
+            ($column:expr, $value:expr) => {{
+                let after: UserFilterExpression<'static> = match direction {
+                    Direction::Asc => Box::new($column.gt($value.clone())),
+                    Direction::Desc => Box::new($column.lt($value.clone())),
+                };
+                let same: UserFilterExpression<'static> = Box::new($column.eq($value));
+                (same, after)
+            }};
+        }
+
+        match self {
+            CursorValue::Hours(seconds, nanos) => {
+                vec![key_expressions!(hours_seconds, seconds), key_expressions!(hours_nanos, nanos)]
+            }
+            CursorValue::Money(value) => vec![key_expressions!(money, value)],
+            CursorValue::DisplayName(value) => vec![key_expressions!(display_name, value)],
+            CursorValue::FirstSeenAt(seconds, nanos) => {
+                vec![key_expressions!(first_seen_at, NaiveDateTime::from_timestamp(seconds, nanos))]
+            }
+            CursorValue::LastSeenAt(seconds, nanos) => {
+                vec![key_expressions!(last_seen_at, NaiveDateTime::from_timestamp(seconds, nanos))]
+            }
+            CursorValue::ChannelId(value) => vec![key_expressions!(channel_id, value)],
+            CursorValue::MessageCount(value) => vec![key_expressions!(message_count, value)],
+        }
+    }
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("cursors are always serializable");
+        json.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn decode(cursor: &str) -> Option<Cursor> {
+        // An odd length leaves half a byte at the end, which makes the last slice fail
+        let json = (0..cursor.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(cursor.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        serde_json::from_slice(&json).ok()
+    }
+}
+
+/// Creates the cursor continuing after a user sorted by the sort keys
+pub fn encode_cursor(user: &User, sort_keys: &[SortKey]) -> String {
+    Cursor {
+        values: sort_keys.iter().map(|key| CursorValue::of(user, key.field())).collect(),
+        channel_id: user.channel_id.clone(),
+    }
+    .encode()
+}
+
+/// Creates the cursor continuing after a channel id, for users sorted by nothing but it
+pub fn encode_channel_id_cursor(channel_id: &str) -> String {
+    Cursor {
+        values: Vec::new(),
+        channel_id: channel_id.to_string(),
+    }
+    .encode()
+}
+
+/// Returns the channel id of a cursor created for users sorted by nothing but it
+pub fn decode_channel_id_cursor(cursor: &str) -> Option<String> {
+    Cursor::decode(cursor)
+        .filter(|cursor| cursor.values.is_empty())
+        .map(|cursor| cursor.channel_id)
+}
+
+/// Builds an expression matching the users sorted after a cursor
+///
+/// Returns None if the cursor is invalid or was created for other sort keys. The users have to be
+/// ordered by the sort keys and then by their channel id.
+pub fn after_cursor_expression(cursor: &str, sort_keys: &[SortKey]) -> Option<UserFilterExpression<'static>> {
+    let cursor = Cursor::decode(cursor)?;
+    if cursor.values.len() != sort_keys.len()
+        || cursor.values.iter().zip(sort_keys).any(|(value, key)| value.field() != key.field())
+    {
+        return None;
+    }
+
+    let mut columns: Vec<_> = cursor
+        .values
+        .into_iter()
+        .zip(sort_keys)
+        .flat_map(|(value, key)| value.expressions(key.direction()))
+        .collect();
+    columns.extend(CursorValue::ChannelId(cursor.channel_id).expressions(Direction::Asc));
+
+    // A user comes after the cursor if it's sorted after it by a column and has the same values
+    // in all columns before, built from the last column to nest the conditions
+    let mut columns = columns.into_iter().rev();
+    let (_, last_after) = columns.next()?;
+    Some(columns.fold(last_after, |later, (same, after)| Box::new(after.or(same.and(later)))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn like_wildcards_are_escaped() {
+        assert_eq!(escape_like("Lumi"), "Lumi");
+        assert_eq!(escape_like("100%"), "100\\%");
+        assert_eq!(escape_like("a_b"), "a\\_b");
+        assert_eq!(escape_like("back\\slash"), "back\\\\slash");
+    }
+
+    fn rank(rank_id: i32, rank_sorting: i32, seconds: i64, nanos: i32) -> Rank {
+        Rank {
+            rank_id,
+            rank_name: format!("rank {}", rank_id),
+            rank_sorting,
+            hour_requirement_seconds: seconds,
+            hour_requirement_nanos: nanos,
+            payout_multiplier: 1.0,
+        }
+    }
+
+    #[test]
+    fn rank_range_ends_at_the_next_rank() {
+        let ranks = [rank(1, 1, 3600, 0), rank(2, 2, 7200, 500), rank(3, 3, 7200, 0)];
+        assert_eq!(rank_hours_range("rank 1", &ranks), Some((Some((3600, 0)), Some((7200, 0)))));
+        assert_eq!(rank_hours_range("rank 3", &ranks), Some((Some((7200, 0)), None)));
+    }
+
+    #[test]
+    fn rank_range_compares_nanos() {
+        // Sorted like Rank::load_by_sorting returns them
+        let ranks = [rank(2, 2, 3600, 400), rank(1, 1, 3600, 0)];
+        assert_eq!(rank_hours_range("rank 1", &ranks), Some((Some((3600, 0)), Some((3600, 400)))));
+        let active_rank_id = |nanos| Rank::active_for_hours(&ranks, 3600, nanos).map(|rank| rank.rank_id);
+        assert_eq!(active_rank_id(399), Some(1));
+        assert_eq!(active_rank_id(400), Some(2));
+    }
+
+    #[test]
+    fn default_rank_ends_at_the_lowest_requirement() {
+        let ranks = [rank(1, 2, 7200, 0), rank(2, 1, 3600, 0)];
+        assert_eq!(rank_hours_range("default", &ranks), Some((None, Some((3600, 0)))));
+        assert_eq!(rank_hours_range("default", &[]), Some((None, None)));
+    }
+
+    #[test]
+    fn unknown_ranks_have_no_range() {
+        assert_eq!(rank_hours_range("unknown", &[rank(1, 1, 3600, 0)]), None);
+    }
+
+    const CHANNEL_ID: &str = "UCabcdefghijklmnopqrstuv";
+
+    fn sort_key(field: Field, direction: Direction) -> SortKey {
+        SortKey {
+            field: field as i32,
+            direction: direction as i32,
+        }
+    }
+
+    fn user() -> User {
+        let seen_at = NaiveDateTime::from_timestamp(1_600_000_000, 0);
+        User::new(CHANNEL_ID.to_string(), "Lumi".to_string(), 3600, 0, 12.5, seen_at, seen_at)
+    }
+
+    fn sql_after(cursor: &str, sort_keys: &[SortKey]) -> String {
+        let expression = after_cursor_expression(cursor, sort_keys).expect("the cursor should be valid");
+        diesel::debug_query::<Pg, _>(&expression).to_string()
+    }
+
+    #[test]
+    fn channel_id_cursor_roundtrips() {
+        let cursor = encode_channel_id_cursor(CHANNEL_ID);
+        assert!(cursor.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(decode_channel_id_cursor(&cursor), Some(CHANNEL_ID.to_string()));
+    }
+
+    #[test]
+    fn invalid_cursors_are_rejected() {
+        let cursor = encode_channel_id_cursor(CHANNEL_ID);
+        assert_eq!(decode_channel_id_cursor(&cursor[..cursor.len() - 1]), None);
+        assert_eq!(decode_channel_id_cursor("not a cursor"), None);
+        assert_eq!(decode_channel_id_cursor(""), None);
+        assert!(after_cursor_expression("zz", &[]).is_none());
+    }
+
+    #[test]
+    fn sorted_cursors_are_not_channel_id_cursors() {
+        let cursor = encode_cursor(&user(), &[sort_key(Field::Money, Direction::Desc)]);
+        assert_eq!(decode_channel_id_cursor(&cursor), None);
+    }
+
+    #[test]
+    fn cursor_must_match_the_sort_keys() {
+        let cursor = encode_cursor(&user(), &[sort_key(Field::Money, Direction::Desc)]);
+        assert!(after_cursor_expression(&cursor, &[]).is_none());
+        assert!(after_cursor_expression(&cursor, &[sort_key(Field::Hours, Direction::Desc)]).is_none());
+        assert!(after_cursor_expression(&cursor, &[sort_key(Field::Money, Direction::Asc)]).is_some());
+    }
+
+    #[test]
+    fn channel_id_cursor_continues_after_the_channel_id() {
+        let sql = sql_after(&encode_channel_id_cursor(CHANNEL_ID), &[]);
+        assert_eq!(
+            sql,
+            format!("\"bpp_users\".\"channel_id\" > $1 -- binds: [{:?}]", CHANNEL_ID)
+        );
+    }
+
+    #[test]
+    fn sort_keys_are_compared_before_the_channel_id() {
+        let sort_keys = [sort_key(Field::Money, Direction::Desc)];
+        let sql = sql_after(&encode_cursor(&user(), &sort_keys), &sort_keys);
+        assert_eq!(
+            sql,
+            format!(
+                "(\"bpp_users\".\"money\" < $1 OR \"bpp_users\".\"money\" = $2 AND \
+                 \"bpp_users\".\"channel_id\" > $3) -- binds: [12.5, 12.5, {:?}]",
+                CHANNEL_ID
+            )
+        );
+    }
+
+    #[test]
+    fn hours_compare_seconds_then_nanos() {
+        let sort_keys = [sort_key(Field::Hours, Direction::Asc)];
+        let sql = sql_after(&encode_cursor(&user(), &sort_keys), &sort_keys);
+        assert_eq!(
+            sql,
+            format!(
+                "(\"bpp_users\".\"hours_seconds\" > $1 OR \"bpp_users\".\"hours_seconds\" = $2 AND \
+                 (\"bpp_users\".\"hours_nanos\" > $3 OR \"bpp_users\".\"hours_nanos\" = $4 AND \
+                 \"bpp_users\".\"channel_id\" > $5)) -- binds: [3600, 3600, 0, 0, {:?}]",
+                CHANNEL_ID
+            )
+        );
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, warn};
+use tokio::sync::watch;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+
+use crate::shutdown::wait_for_shutdown;
+use crate::userservice::user_service_server::UserServiceServer;
+use crate::{DbPool, UserServer};
+
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const DATABASE_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+/// The health service name reporting whether messages from youtubeservice are ingested
+pub const INGEST_HEALTH_SERVICE: &str = "userservice.ingest";
+
+/// Checks if a connection can be taken from the pool without blocking the runtime
+async fn database_is_healthy(pool: &DbPool) -> bool {
+    let pool = pool.clone();
+    let result =
+        tokio::task::spawn_blocking(move || pool.get_timeout(DATABASE_CHECK_TIMEOUT).map(|_| ())).await;
+    match result {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            warn!("Health check could not get a database connection: {}", e);
+            false
+        }
+        Err(_) => false,
+    }
+}
+
+/// Periodically updates the gRPC health status of the userservice
+///
+/// The service is reported as SERVING while the database is reachable, so users can be queried
+/// during an outage of youtubeservice. Whether the message stream is connected is reported
+/// separately as the ingest.
+pub async fn report_health(
+    mut reporter: HealthReporter,
+    pool: DbPool,
+    youtube_connected: Arc<AtomicBool>,
+    shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        let database_healthy = database_is_healthy(&pool).await;
+        let youtube_healthy = youtube_connected.load(Ordering::Relaxed);
+        debug!(
+            "Health check: database {}, youtubeservice {}",
+            database_healthy, youtube_healthy
+        );
+        if database_healthy {
+            reporter.set_serving::<UserServiceServer<UserServer>>().await;
+        } else {
+            reporter.set_not_serving::<UserServiceServer<UserServer>>().await;
+        }
+        let ingest_status = if database_healthy && youtube_healthy {
+            ServingStatus::Serving
+        } else {
+            ServingStatus::NotServing
+        };
+        reporter.set_service_status(INGEST_HEALTH_SERVICE, ingest_status).await;
+
+        tokio::select! {
+            _ = tokio::time::sleep(HEALTH_CHECK_INTERVAL) => {}
+            _ = wait_for_shutdown(shutdown.clone()) => {
+                reporter.set_not_serving::<UserServiceServer<UserServer>>().await;
+                reporter.set_service_status(INGEST_HEALTH_SERVICE, ServingStatus::NotServing).await;
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,676 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use log::{debug, error, info, warn};
+use tokio::sync::{broadcast, watch};
+use tonic::transport::Channel;
+use tonic::Request;
+use tracing::Instrument;
+
+use crate::log::log_fields;
+use crate::metrics::{
+    message_totals, record_dropped_messages, record_ingested_message, record_processed_messages,
+    record_skipped_message,
+};
+use crate::models::{normalize_channel_id, Group, GroupUser, IngestPosition, PreviousName, Rank, User};
+use crate::settings::Settings;
+use crate::userservice::RankUpEvent;
+use crate::shutdown::wait_for_shutdown;
+use crate::youtubeservice::you_tube_service_client::YouTubeServiceClient;
+use crate::youtubeservice::YouTubeChatMessage;
+use crate::{DbPool, Void};
+
+const NANOS_PER_SECOND: i64 = 1_000_000_000;
+const RECENT_MESSAGE_CAPACITY: usize = 10_000;
+
+/// Everything a user did between two flushes of the message buffer
+struct BufferedActivity {
+    display_name: String,
+    first_seen_at: NaiveDateTime,
+    last_seen_at: NaiveDateTime,
+    /// When the first and the last message long enough to count as activity were seen
+    active_period: Option<(NaiveDateTime, NaiveDateTime)>,
+    /// Whether the latest message of the user came from a channel member
+    is_member: bool,
+    message_count: i64,
+}
+
+/// Collects chat activity per user, so a user chatting many times in a row only causes one write
+#[derive(Default)]
+struct MessageBuffer {
+    activities: HashMap<String, BufferedActivity>,
+    message_count: u64,
+    /// The most recently published message of the buffer
+    position: Option<(String, NaiveDateTime)>,
+}
+
+impl MessageBuffer {
+    fn push(&mut self, message: YouTubeChatMessage, seen_at: NaiveDateTime, counts_as_active: bool) {
+        self.message_count += 1;
+        if let Some(published_at) = published_at(&message) {
+            let is_newer = match &self.position {
+                Some((_, last_published_at)) => published_at >= *last_published_at,
+                None => true,
+            };
+            if is_newer {
+                self.position = Some((message.message_id.clone(), published_at));
+            }
+        }
+        match self.activities.get_mut(&message.channel_id) {
+            Some(activity) => {
+                activity.display_name = message.display_name;
+                activity.last_seen_at = seen_at;
+                if counts_as_active {
+                    let first_active_at = match activity.active_period {
+                        Some((first_active_at, _)) => first_active_at,
+                        None => seen_at,
+                    };
+                    activity.active_period = Some((first_active_at, seen_at));
+                }
+                activity.is_member = message.is_member;
+                activity.message_count += 1;
+            }
+            None => {
+                let activity = BufferedActivity {
+                    display_name: message.display_name,
+                    first_seen_at: seen_at,
+                    last_seen_at: seen_at,
+                    active_period: if counts_as_active { Some((seen_at, seen_at)) } else { None },
+                    is_member: message.is_member,
+                    message_count: 1,
+                };
+                self.activities.insert(message.channel_id, activity);
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.activities.is_empty()
+    }
+}
+
+/// Converts the publishing time of a message, which youtubeservice might leave out
+fn published_at(message: &YouTubeChatMessage) -> Option<NaiveDateTime> {
+    message
+        .published_at
+        .as_ref()
+        .and_then(|t| NaiveDateTime::from_timestamp_opt(t.seconds, t.nanos as u32))
+}
+
+/// Loads where the previous subscription stopped, a failure only means nothing is skipped
+async fn load_ingest_position(pool: &DbPool) -> Option<IngestPosition> {
+    let pool = pool.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        pool.get()
+            .map_err(|e| e.to_string())
+            .and_then(|conn| IngestPosition::load(&conn).map_err(|e| e.to_string()))
+    })
+    .await
+    .unwrap_or_else(|e| Err(e.to_string()));
+    match result {
+        Ok(position) => position,
+        Err(e) => {
+            warn!("Failed to load the ingest position, no messages will be skipped: {}", e);
+            None
+        }
+    }
+}
+
+/// Remembers the ids of the most recent messages, so redelivered messages can be skipped
+///
+/// Only the last `RECENT_MESSAGE_CAPACITY` ids are kept, which covers what youtubeservice
+/// replays after a reconnect.
+#[derive(Default)]
+struct RecentMessages {
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl RecentMessages {
+    /// Records a message id and returns false if it was already seen
+    fn insert(&mut self, message_id: &str) -> bool {
+        // Without an id a message can't be recognized again
+        if message_id.is_empty() {
+            return true;
+        }
+        if self.ids.contains(message_id) {
+            return false;
+        }
+        if self.order.len() >= RECENT_MESSAGE_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.ids.insert(message_id.to_string());
+        self.order.push_back(message_id.to_string());
+        true
+    }
+}
+
+/// Adds a duration to stored hours, carrying full seconds over from the nanoseconds
+///
+/// Saturates instead of overflowing, so a corrupted timestamp can't crash the ingest.
+fn add_to_hours(seconds: i64, nanos: i32, duration: chrono::Duration) -> (i64, i32) {
+    let added_seconds = duration.num_seconds();
+    // What's left after the full seconds is always less than a second, so this can't be None
+    let added_nanos = (duration - chrono::Duration::seconds(added_seconds))
+        .num_nanoseconds()
+        .unwrap_or(0);
+
+    let total_nanos = nanos as i64 + added_nanos;
+    let carried_seconds = total_nanos.div_euclid(NANOS_PER_SECOND);
+    let new_seconds = seconds
+        .saturating_add(added_seconds)
+        .saturating_add(carried_seconds);
+    (new_seconds, total_nanos.rem_euclid(NANOS_PER_SECOND) as i32)
+}
+
+/// Grants hours and money to a user for the time that passed since they were last seen
+///
+/// `new_duration` has to be computed from the `last_seen_at` from before the current message.
+/// Money is paid for `paid_duration`, which `payout_duration` decides. Channel members get the
+/// member multiplier on top of the rank multiplier.
+/// `ranks` have to be sorted like `Rank::load_by_sorting` returns them and `groups` are the groups
+/// of the user, both are loaded once for the whole buffer.
+/// Returns an event if the new hours moved the user into a higher rank. The user isn't named in the
+/// log messages, the caller attaches them with `log_fields`.
+fn calculate_hours_and_money(
+    user: &mut User,
+    new_duration: chrono::Duration,
+    paid_duration: chrono::Duration,
+    is_member: bool,
+    ranks: &[Rank],
+    groups: &[Group],
+    settings: &Settings,
+) -> Option<RankUpEvent> {
+    let old_rank = Rank::active_for_hours(ranks, user.hours_seconds, user.hours_nanos);
+    debug!("Between the last time the user was seen and now, {} seconds have passed", new_duration.num_seconds());
+    let (new_hours_seconds, new_hours_nanos) =
+        add_to_hours(user.hours_seconds, user.hours_nanos, new_duration);
+    let old_hours = user.hours();
+    user.hours_seconds = new_hours_seconds;
+    user.hours_nanos = new_hours_nanos;
+    debug!("Updated hours from {:.4}h to {:.4}h", old_hours, user.hours());
+
+    // Grant x money per minute
+    let mut money_per_minute: f64 = settings.default_payout as f64;
+    for group in groups {
+        money_per_minute += group.bonus_payout as f64;
+    }
+    // The rank the user qualifies for with their new hours multiplies the payout
+    let new_rank = Rank::active_for_hours(ranks, user.hours_seconds, user.hours_nanos);
+    if let Some(rank) = &new_rank {
+        money_per_minute *= rank.payout_multiplier;
+    }
+    if is_member {
+        money_per_minute *= settings.member_multiplier;
+    }
+    let money_per_second: f64 = money_per_minute / 60.0;
+
+    let new_money = user.money + money_per_second * paid_duration.num_milliseconds() as f64 / 1000.0;
+    debug!("Updating money from {:.2} to {:.2}", user.money, new_money);
+    user.money = new_money;
+
+    // Hours only ever grow, so a different rank is always a higher one
+    let new_rank = new_rank?;
+    if old_rank.map(|rank| rank.rank_id) == Some(new_rank.rank_id) {
+        return None;
+    }
+    let _fields = log_fields(&[("rank", &new_rank.rank_name)]);
+    info!("User reached a new rank");
+    Some(RankUpEvent {
+        channel_id: user.channel_id.clone(),
+        display_name: user.display_name.clone(),
+        old_rank: old_rank.map_or_else(|| "default".to_string(), |rank| rank.rank_name.clone()),
+        new_rank: new_rank.rank_name.clone(),
+    })
+}
+
+/// Decides for how much time a user gets paid now, updating their payout bookkeeping
+///
+/// With `money_requires_chat` only users who chatted within the active window before get paid.
+/// During the payout cooldown the credited time is kept and paid with the next payout.
+fn payout_duration(
+    user: &mut User,
+    credited: chrono::Duration,
+    chatted_recently: bool,
+    now: NaiveDateTime,
+    settings: &Settings,
+) -> chrono::Duration {
+    if settings.money_requires_chat && !chatted_recently {
+        return chrono::Duration::zero();
+    }
+
+    let unpaid = chrono::Duration::milliseconds(user.unpaid_millis) + credited;
+    let cooldown = chrono::Duration::seconds(settings.payout_cooldown_seconds.max(0) as i64);
+    let cooling_down = matches!(user.last_paid_at, Some(last_paid_at) if now - last_paid_at < cooldown);
+    if cooling_down {
+        user.unpaid_millis = unpaid.num_milliseconds();
+        return chrono::Duration::zero();
+    }
+    user.unpaid_millis = 0;
+    user.last_paid_at = Some(now);
+    unpaid
+}
+
+/// Writes all buffered activity to the database in one transaction and empties the buffer
+///
+/// Rank-ups are only announced once the transaction is committed. The queries run on the blocking
+/// threads, so a slow database doesn't stall the runtime.
+async fn flush_buffer(
+    buffer: &mut MessageBuffer,
+    pool: &DbPool,
+    settings: &Settings,
+    rank_ups: &broadcast::Sender<RankUpEvent>,
+) -> Void {
+    if buffer.is_empty() {
+        return Ok(());
+    }
+
+    let message_count = std::mem::take(&mut buffer.message_count);
+    let activities = std::mem::take(&mut buffer.activities);
+    let position = buffer.position.take();
+    let last_seen_at = activities.values().map(|activity| activity.last_seen_at).max();
+    let (pool, settings) = (pool.clone(), settings.clone());
+    let saved = tokio::task::spawn_blocking(move || {
+        save_activities(activities, position, &pool, &settings).map_err(|e| e.to_string())
+    })
+    .await
+    .unwrap_or_else(|e| Err(e.to_string()));
+    let events = match saved {
+        Ok(events) => events,
+        Err(e) => {
+            record_dropped_messages(message_count);
+            return Err(e.into());
+        }
+    };
+    if let Some(last_seen_at) = last_seen_at {
+        record_processed_messages(message_count, last_seen_at);
+    }
+
+    for event in events {
+        // Sending only fails if nobody is subscribed, which is fine
+        let _ = rank_ups.send(event);
+    }
+
+    Ok(())
+}
+
+/// Credits the buffered activity to the users in one transaction
+///
+/// The ingest position is moved to the last buffered message in the same transaction, so it
+/// never points past activity that wasn't saved.
+fn save_activities(
+    activities: HashMap<String, BufferedActivity>,
+    position: Option<(String, NaiveDateTime)>,
+    pool: &DbPool,
+    settings: &Settings,
+) -> Result<Vec<RankUpEvent>, Box<dyn std::error::Error>> {
+    let conn = pool.get()?;
+    let active_time = chrono::Duration::seconds(settings.active_time as i64);
+    let max_credit = chrono::Duration::seconds(settings.max_credit_seconds as i64);
+    // Users are locked in the order of their channel id, like everywhere else, to avoid deadlocks
+    let mut activities: Vec<(String, BufferedActivity)> = activities.into_iter().collect();
+    activities.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+    debug!("Flushing activity of {} users", activities.len());
+    let _span = tracing::info_span!("flush_buffer", users = activities.len()).entered();
+
+    let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+        // Deleted users keep their history as it was, so nothing of their activity is stored
+        let channel_ids: Vec<&str> = activities.iter().map(|(channel_id, _)| channel_id.as_str()).collect();
+        let deleted = User::find_deleted(&channel_ids, &conn)?;
+
+        let mut seen = Vec::with_capacity(activities.len());
+        for (channel_id, activity) in activities {
+            let _span = tracing::debug_span!("save_activity", channel_id = channel_id.as_str()).entered();
+            let _fields = log_fields(&[
+                ("channel_id", &channel_id),
+                ("display_name", &activity.display_name),
+            ]);
+            if deleted.contains(&channel_id) {
+                debug!("Ignoring activity of deleted user");
+                continue;
+            }
+            debug!("Saving activity");
+            if settings.track_previous_names {
+                let changed_at = activity.first_seen_at;
+                if PreviousName::record_if_changed(&channel_id, &activity.display_name, changed_at, &conn)? > 0 {
+                    debug!("Recorded the previous display name");
+                }
+            }
+            let (user, created) = User::upsert_seen(
+                &channel_id,
+                &activity.display_name,
+                activity.first_seen_at,
+                activity.message_count,
+                &conn,
+            )?;
+            if created {
+                debug!("Created new user");
+                GroupUser::add_to_default_group(&settings.default_group, &channel_id, &conn)?;
+            }
+            seen.push((channel_id, activity, user, created));
+        }
+
+        // Ranks and groups are loaded once for the whole buffer instead of for every user. The
+        // groups are loaded after the new users joined the default group, so they get its bonus.
+        let ranks = Rank::load_by_sorting(&conn)?;
+        let channel_ids: Vec<&str> = seen.iter().map(|(channel_id, ..)| channel_id.as_str()).collect();
+        let mut groups: HashMap<String, Vec<Group>> = HashMap::new();
+        for (member_channel_id, group) in Group::find_for_users(&channel_ids, &conn)? {
+            groups.entry(member_channel_id).or_default().push(group);
+        }
+
+        let mut users = Vec::with_capacity(seen.len());
+        let mut events = Vec::new();
+        for (channel_id, activity, mut user, created) in seen {
+            let _span = tracing::debug_span!("save_activity", channel_id = channel_id.as_str()).entered();
+            let _fields = log_fields(&[
+                ("channel_id", &channel_id),
+                ("display_name", &activity.display_name),
+            ]);
+            // A user deleted while the buffer is saved doesn't earn anything anymore either
+            if user.deleted_at.is_some() {
+                debug!("Ignoring activity of deleted user");
+                continue;
+            }
+            // The name and message count are already stored, the activity clock keeps running
+            let (first_active_at, last_active_at) = match activity.active_period {
+                Some(active_period) => active_period,
+                None => {
+                    debug!("No message was long enough to count as activity");
+                    continue;
+                }
+            };
+
+            // Determine if user was active before the first buffered message and if so, credit
+            // the gap. The gap has to be taken from the stored last_seen_at, before it's
+            // overwritten below. The messages inside the buffer are always close together,
+            // so the time between the first and the last one is credited as well.
+            // A user who was just created has no time before their first message to credit.
+            let mut credited = last_active_at - first_active_at;
+            let gap = first_active_at - user.last_seen_at;
+            // Messages older than the stored last_seen_at arrive late and have no gap to credit
+            let chatted_recently = !created && gap >= chrono::Duration::zero() && gap < active_time;
+            if chatted_recently {
+                credited = credited + gap;
+            }
+            // A jumping clock could otherwise credit hours nobody actually watched
+            if credited > max_credit {
+                warn!(
+                    "Capping credit from {}s to {}s, check the clocks of the services",
+                    credited.num_seconds(),
+                    max_credit.num_seconds()
+                );
+                credited = max_credit;
+            }
+            let paid = payout_duration(&mut user, credited, chatted_recently, last_active_at, settings);
+            if credited > chrono::Duration::zero() || paid > chrono::Duration::zero() {
+                let (hours_before, money_before) = (user.hours_seconds, user.money);
+                events.extend(calculate_hours_and_money(
+                    &mut user,
+                    credited,
+                    paid,
+                    activity.is_member,
+                    &ranks,
+                    groups.get(&channel_id).map_or(&[][..], Vec::as_slice),
+                    settings,
+                ));
+                if settings.dry_run {
+                    info!(
+                        "Dry run: user would get {}s and {:.2} money",
+                        user.hours_seconds - hours_before,
+                        user.money - money_before
+                    );
+                }
+            }
+            // A late batch must not move last_seen_at back, or its gap gets credited twice
+            user.last_seen_at = user.last_seen_at.max(last_active_at);
+
+            users.push(user);
+        }
+
+        if settings.dry_run {
+            return Err(diesel::result::Error::RollbackTransaction);
+        }
+
+        // Update the users
+        User::upsert_many(&users, &conn)?;
+        if let Some((message_id, published_at)) = &position {
+            IngestPosition::save(message_id, *published_at, &conn)?;
+        }
+        Ok(events)
+    });
+
+    match result {
+        Ok(events) => Ok(events),
+        // A dry run always rolls back, which also discards the users it created
+        Err(diesel::result::Error::RollbackTransaction) if settings.dry_run => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn fetch_users_from_messages(
+    youtube_client: &mut YouTubeServiceClient<Channel>,
+    pool: &DbPool,
+    settings: &Settings,
+    youtube_connected: &AtomicBool,
+    rank_ups: &broadcast::Sender<RankUpEvent>,
+    recent_messages: &mut RecentMessages,
+    shutdown: &watch::Receiver<bool>,
+) -> Void {
+    let subscribe_timeout = Duration::from_secs(settings.youtube_timeout_seconds.max(1) as u64);
+    let subscription = tokio::time::timeout(subscribe_timeout, youtube_client.subscribe_messages(Request::new(())));
+    let mut stream = match subscription.await {
+        Ok(subscription) => subscription?.into_inner(),
+        Err(_) => return Err(format!("Subscribing took longer than {}s", subscribe_timeout.as_secs()).into()),
+    };
+    youtube_connected.store(true, Ordering::Relaxed);
+
+    // youtubeservice has no way to start a subscription at a given message, so whatever it
+    // replays from before the stored position is skipped here instead
+    let resume_position = load_ingest_position(pool).await;
+    if let Some(position) = &resume_position {
+        info!(
+            "Resuming after message {} published at {}",
+            position.last_message_id, position.last_published_at
+        );
+    }
+
+    let mut buffer = MessageBuffer::default();
+    let mut flush_interval =
+        tokio::time::interval(Duration::from_millis(settings.message_buffer_ms as u64));
+    let stall_timeout = Duration::from_secs(settings.stall_warning_seconds as u64);
+    let min_message_length = settings.min_message_length.max(0) as usize;
+    let mut last_message_at = Instant::now();
+    let mut stall_reported = false;
+
+    // Shutdown is only checked between messages, so the current message is always finished
+    let result = loop {
+        tokio::select! {
+            message = stream.message() => match message {
+                Ok(Some(mut message)) => {
+                    let _fields = log_fields(&[
+                        ("message_id", &message.message_id),
+                        ("channel_id", &message.channel_id),
+                    ]);
+                    record_ingested_message();
+                    last_message_at = Instant::now();
+                    stall_reported = false;
+                    if !recent_messages.insert(&message.message_id) {
+                        debug!("Skipping redelivered message");
+                        record_skipped_message("redelivered");
+                        continue;
+                    }
+                    let already_saved = match (&resume_position, published_at(&message)) {
+                        (Some(position), Some(published_at)) => {
+                            position.covers(&message.message_id, published_at)
+                        }
+                        _ => false,
+                    };
+                    if already_saved {
+                        debug!("Skipping message from before the ingest position");
+                        record_skipped_message("already_saved");
+                        continue;
+                    }
+                    match normalize_channel_id(&message.channel_id) {
+                        Some(channel_id) => {
+                            message.channel_id = channel_id.to_string();
+                            let counts_as_active = message.message.trim().chars().count() >= min_message_length;
+                            buffer.push(message, Utc::now().naive_utc(), counts_as_active);
+                        }
+                        None => {
+                            warn!("Skipping message with malformed channel id");
+                            record_skipped_message("malformed_channel_id");
+                        }
+                    }
+                }
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e.into()),
+            },
+            _ = flush_interval.tick() => {
+                if let Err(e) = flush_buffer(&mut buffer, pool, settings, rank_ups).await {
+                    error!("Failed to save buffered messages: {}", e);
+                }
+
+                // A quiet chat and a stuck stream look the same, so this is only a warning
+                if !stall_reported && last_message_at.elapsed() > stall_timeout {
+                    let (processed, dropped) = message_totals();
+                    warn!(
+                        "No message from youtubeservice for {}s although the stream is connected \
+                         ({} messages processed, {} dropped so far)",
+                        last_message_at.elapsed().as_secs(),
+                        processed,
+                        dropped
+                    );
+                    stall_reported = true;
+                }
+            }
+            _ = wait_for_shutdown(shutdown.clone()) => break Ok(()),
+        }
+    };
+
+    // Whatever was buffered when the stream stopped still has to be saved
+    if let Err(e) = flush_buffer(&mut buffer, pool, settings, rank_ups).await {
+        error!("Failed to save buffered messages: {}", e);
+    }
+    let (processed, dropped) = message_totals();
+    info!("{} messages processed, {} dropped so far", processed, dropped);
+
+    result
+}
+
+/// Keeps the message subscription alive, resubscribing with an exponential backoff
+/// whenever the stream ends or fails, until shutdown is requested
+pub async fn ingest_messages(
+    youtube_client: &mut YouTubeServiceClient<Channel>,
+    pool: &DbPool,
+    settings: &Settings,
+    youtube_connected: Arc<AtomicBool>,
+    rank_ups: broadcast::Sender<RankUpEvent>,
+    shutdown: watch::Receiver<bool>,
+) {
+    let initial_backoff = Duration::from_secs(1);
+    let max_backoff = Duration::from_secs(30);
+    let mut backoff = initial_backoff;
+    // Kept across subscriptions, since redeliveries happen after a reconnect
+    let mut recent_messages = RecentMessages::default();
+
+    loop {
+        let subscribed_at = Instant::now();
+        let result = fetch_users_from_messages(
+            youtube_client,
+            pool,
+            settings,
+            &youtube_connected,
+            &rank_ups,
+            &mut recent_messages,
+            &shutdown,
+        )
+        .instrument(tracing::info_span!("message_subscription"))
+        .await;
+        youtube_connected.store(false, Ordering::Relaxed);
+        if *shutdown.borrow() {
+            info!("Stopped message fetching");
+            return;
+        }
+        match result {
+            Ok(()) => warn!("Message stream from youtubeservice ended"),
+            Err(e) => error!("Message stream from youtubeservice failed: {}", e),
+        }
+
+        // A subscription that stayed up for a while counts as healthy again
+        if subscribed_at.elapsed() > max_backoff {
+            backoff = initial_backoff;
+        }
+        info!("Resubscribing to youtubeservice in {}s", backoff.as_secs());
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = wait_for_shutdown(shutdown.clone()) => {
+                info!("Stopped message fetching");
+                return;
+            }
+        }
+        backoff = std::cmp::min(backoff * 2, max_backoff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(channel_id: &str, display_name: &str) -> YouTubeChatMessage {
+        YouTubeChatMessage {
+            channel_id: channel_id.to_string(),
+            display_name: display_name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn at(seconds: i64) -> NaiveDateTime {
+        NaiveDateTime::from_timestamp(1_600_000_000 + seconds, 0)
+    }
+
+    #[test]
+    fn buffer_merges_the_messages_of_a_user() {
+        let mut buffer = MessageBuffer::default();
+        assert!(buffer.is_empty());
+        buffer.push(message("UC1", "Lumi"), at(0), true);
+        buffer.push(message("UC2", "Other"), at(5), true);
+        buffer.push(message("UC1", "Lumi Renamed"), at(10), true);
+
+        assert_eq!(buffer.activities.len(), 2);
+        assert_eq!(buffer.message_count, 3);
+        let activity = &buffer.activities["UC1"];
+        assert_eq!(activity.display_name, "Lumi Renamed");
+        assert_eq!(activity.message_count, 2);
+        assert_eq!((activity.first_seen_at, activity.last_seen_at), (at(0), at(10)));
+        assert_eq!(activity.active_period, Some((at(0), at(10))));
+    }
+
+    #[test]
+    fn redelivered_messages_are_recognized() {
+        let mut recent = RecentMessages::default();
+        assert!(recent.insert("message 1"));
+        assert!(recent.insert("message 2"));
+        assert!(!recent.insert("message 1"));
+        // Messages without an id can't be told apart, so none of them is skipped
+        assert!(recent.insert(""));
+        assert!(recent.insert(""));
+    }
+
+    #[test]
+    fn only_the_most_recent_messages_are_remembered() {
+        let mut recent = RecentMessages::default();
+        for id in 0..=RECENT_MESSAGE_CAPACITY {
+            assert!(recent.insert(&id.to_string()));
+        }
+        assert_eq!(recent.ids.len(), RECENT_MESSAGE_CAPACITY);
+        assert!(recent.insert("0"));
+        assert!(!recent.insert(&RECENT_MESSAGE_CAPACITY.to_string()));
+    }
+}
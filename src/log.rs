@@ -1,9 +1,130 @@
+use std::cell::RefCell;
+use std::fmt::Display;
+
 use fern::{
     colors::{Color, ColoredLevelConfig},
 };
 
+thread_local! {
+    /// Fields added to every line logged on this thread, see `log_fields`
+    // A const initializer needs a newer compiler than the one the Dockerfile builds with
+    #[allow(clippy::missing_const_for_thread_local)]
+    static FIELDS: RefCell<Vec<(&'static str, String)>> = RefCell::new(Vec::new());
+}
+
+/// How log lines are written
+#[derive(Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// Colored lines for people reading the terminal
+    Text,
+    /// One JSON object per line for log aggregators
+    Json,
+}
+
+impl LogFormat {
+    /// Parses the value of `LOG_FORMAT`, which defaults to text
+    pub fn parse(format: Option<&str>) -> Result<LogFormat, String> {
+        match format.map(str::trim) {
+            None | Some("") | Some("text") => Ok(LogFormat::Text),
+            Some("json") => Ok(LogFormat::Json),
+            Some(other) => Err(format!("Unknown log format {:?}, expected text or json", other)),
+        }
+    }
+}
+
+/// Removes the fields of a `log_fields` scope when dropped
+pub struct LogFields {
+    count: usize,
+}
+
+impl Drop for LogFields {
+    fn drop(&mut self) {
+        FIELDS.with(|fields| {
+            let mut fields = fields.borrow_mut();
+            let len = fields.len() - self.count;
+            fields.truncate(len);
+        });
+    }
+}
+
+/// Attaches fields to everything logged on the current thread until the returned guard is dropped
+///
+/// JSON lines carry the fields as keys next to the message, text lines append them as
+/// `key=value`. Scopes nest, the fields of inner scopes come last.
+pub fn log_fields(fields: &[(&'static str, &dyn Display)]) -> LogFields {
+    FIELDS.with(|current| {
+        current
+            .borrow_mut()
+            .extend(fields.iter().map(|(key, value)| (*key, value.to_string())));
+    });
+    LogFields { count: fields.len() }
+}
+
+/// Formats a record as a single JSON object
+fn json_line(message: &std::fmt::Arguments, record: &log::Record) -> String {
+    let mut line = serde_json::Map::new();
+    FIELDS.with(|fields| {
+        for (key, value) in fields.borrow().iter() {
+            line.insert(key.to_string(), value.clone().into());
+        }
+    });
+    line.insert("timestamp".to_string(), chrono::Utc::now().to_rfc3339().into());
+    line.insert("level".to_string(), record.level().as_str().into());
+    line.insert("target".to_string(), record.target().into());
+    line.insert("message".to_string(), message.to_string().into());
+    serde_json::Value::Object(line).to_string()
+}
+
+/// Formats the fields of the current scope as ` key=value` pairs
+fn text_fields() -> String {
+    FIELDS.with(|fields| {
+        fields
+            .borrow()
+            .iter()
+            .map(|(key, value)| format!(" {}={}", key, value))
+            .collect()
+    })
+}
+
+/// Log levels parsed from a `RUST_LOG` style directive like `info,tonic=warn,h2=error`
+///
+/// A bare level sets the default level, `target=level` sets the level of a module and everything
+/// below it. The modules of the server are below `userservice_server`.
+struct LevelDirectives {
+    default: Option<log::LevelFilter>,
+    modules: Vec<(String, log::LevelFilter)>,
+    invalid: Vec<String>,
+}
+
+impl LevelDirectives {
+    fn parse(directives: &str) -> LevelDirectives {
+        let mut parsed = LevelDirectives {
+            default: None,
+            modules: Vec::new(),
+            invalid: Vec::new(),
+        };
+        for directive in directives.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let (target, level) = match directive.find('=') {
+                Some(index) => (Some(directive[..index].trim()), directive[index + 1..].trim()),
+                None => (None, directive),
+            };
+            match (target, level.parse::<log::LevelFilter>()) {
+                (None, Ok(level)) => parsed.default = Some(level),
+                (Some(target), Ok(level)) if !target.is_empty() => {
+                    parsed.modules.push((target.to_string(), level))
+                }
+                _ => parsed.invalid.push(directive.to_string()),
+            }
+        }
+        parsed
+    }
+}
+
 /// Sets up regular logging
-pub fn setup_log(verbose: bool) {
+///
+/// `verbose` switches the default level from info to debug. Level directives, usually from
+/// `LOG_LEVEL` or `RUST_LOG`, override it and can set levels per module.
+pub fn setup_log(verbose: bool, directives: Option<&str>, format: LogFormat) {
     let colors_line = ColoredLevelConfig::new()
         .error(Color::Red)
         .warn(Color::Yellow)
@@ -12,17 +133,26 @@ pub fn setup_log(verbose: bool) {
         .trace(Color::BrightBlack);
     let colors_level = colors_line.info(Color::Green);
 
+    let directives = LevelDirectives::parse(directives.unwrap_or_default());
+    let default_level = directives.default.unwrap_or(if verbose {
+        log::LevelFilter::Debug
+    } else {
+        log::LevelFilter::Info
+    });
+    let mut levels = fern::Dispatch::new().level(default_level);
+    for (target, level) in directives.modules {
+        levels = levels.level_for(target, level);
+    }
+
     fern::Dispatch::new()
         .chain(
-            fern::Dispatch::new()
-                .level(if verbose {
-                    log::LevelFilter::Debug
-                } else {
-                    log::LevelFilter::Info
-                })
+            levels
                 .format(move |out, message, record| {
+                    if format == LogFormat::Json {
+                        return out.finish(format_args!("{}", json_line(message, record)));
+                    }
                     out.finish(format_args!(
-                        "{color_line}[{date}][{target}][{level}{color_line}] {message}\x1B[0m",
+                        "{color_line}[{date}][{target}][{level}{color_line}] {message}{fields}\x1B[0m",
                         color_line = format_args!(
                             "\x1B[{}m",
                             colors_line.get_color(&record.level()).to_fg_str()
@@ -31,10 +161,15 @@ pub fn setup_log(verbose: bool) {
                         target = record.target(),
                         level = colors_level.color(record.level()),
                         message = message,
+                        fields = text_fields(),
                     ));
                 })
                 .chain(std::io::stdout()),
         )
         .apply()
         .unwrap();
-}
\ No newline at end of file
+
+    for directive in directives.invalid {
+        log::warn!("Ignoring invalid log level directive {:?}", directive);
+    }
+}
@@ -0,0 +1,155 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+use chrono::NaiveDateTime;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use lazy_static::lazy_static;
+use log::{error, info};
+use prometheus::{
+    register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Encoder, Histogram, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use r2d2::event::{CheckinEvent, CheckoutEvent, HandleEvent};
+use tokio::sync::watch;
+
+use crate::shutdown::wait_for_shutdown;
+use crate::DbPool;
+
+lazy_static! {
+    static ref GRPC_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "userservice_grpc_requests_total",
+        "Number of gRPC requests per method",
+        &["method"]
+    )
+    .unwrap();
+    static ref DB_CHECKOUT_DURATION: Histogram = register_histogram!(
+        "userservice_db_checkout_duration_seconds",
+        "How long it took to get a connection from the pool"
+    )
+    .unwrap();
+    static ref DB_CONNECTION_DURATION: Histogram = register_histogram!(
+        "userservice_db_connection_duration_seconds",
+        "How long a connection was used for queries before it was returned to the pool"
+    )
+    .unwrap();
+    static ref DB_CONNECTIONS: IntGauge = register_int_gauge!(
+        "userservice_db_connections",
+        "Number of open connections in the pool"
+    )
+    .unwrap();
+    static ref DB_IDLE_CONNECTIONS: IntGauge = register_int_gauge!(
+        "userservice_db_idle_connections",
+        "Number of idle connections in the pool"
+    )
+    .unwrap();
+    static ref INGESTED_MESSAGES: IntCounter = register_int_counter!(
+        "userservice_ingested_messages_total",
+        "Number of chat messages received from youtubeservice"
+    )
+    .unwrap();
+    static ref PROCESSED_MESSAGES: IntCounter = register_int_counter!(
+        "userservice_processed_messages_total",
+        "Number of chat messages which were saved to the database"
+    )
+    .unwrap();
+    static ref DROPPED_MESSAGES: IntCounter = register_int_counter!(
+        "userservice_dropped_messages_total",
+        "Number of chat messages which were lost because saving them failed"
+    )
+    .unwrap();
+    static ref SKIPPED_MESSAGES: IntCounterVec = register_int_counter_vec!(
+        "userservice_skipped_messages_total",
+        "Number of chat messages which were deliberately not saved, per reason",
+        &["reason"]
+    )
+    .unwrap();
+    static ref LAST_PROCESSED_MESSAGE: IntGauge = register_int_gauge!(
+        "userservice_last_processed_message_timestamp_seconds",
+        "Unix timestamp of the last chat message which was saved to the database"
+    )
+    .unwrap();
+}
+
+/// Counts an incoming gRPC request
+pub fn record_request(method: &str) {
+    GRPC_REQUESTS.with_label_values(&[method]).inc();
+}
+
+/// Counts a chat message received from youtubeservice
+pub fn record_ingested_message() {
+    INGESTED_MESSAGES.inc();
+}
+
+/// Counts chat messages which were saved, `last_seen_at` being the time of the newest one
+pub fn record_processed_messages(count: u64, last_seen_at: NaiveDateTime) {
+    PROCESSED_MESSAGES.inc_by(count);
+    LAST_PROCESSED_MESSAGE.set(last_seen_at.timestamp());
+}
+
+/// Counts chat messages which couldn't be saved because flushing them failed
+pub fn record_dropped_messages(count: u64) {
+    DROPPED_MESSAGES.inc_by(count);
+}
+
+/// Counts a chat message which was ignored on purpose, e.g. because its channel id is malformed
+pub fn record_skipped_message(reason: &str) {
+    SKIPPED_MESSAGES.with_label_values(&[reason]).inc();
+}
+
+/// Returns how many chat messages were saved and dropped since the start
+pub fn message_totals() -> (u64, u64) {
+    (PROCESSED_MESSAGES.get(), DROPPED_MESSAGES.get())
+}
+
+/// Records how long connections are waited for and used
+#[derive(Debug)]
+pub struct PoolMetrics;
+
+impl HandleEvent for PoolMetrics {
+    fn handle_checkout(&self, event: CheckoutEvent) {
+        DB_CHECKOUT_DURATION.observe(event.duration().as_secs_f64());
+    }
+
+    fn handle_checkin(&self, event: CheckinEvent) {
+        DB_CONNECTION_DURATION.observe(event.duration().as_secs_f64());
+    }
+}
+
+fn render_metrics(pool: &DbPool) -> Response<Body> {
+    // The pool state is only read when it's scraped
+    let state = pool.state();
+    DB_CONNECTIONS.set(state.connections as i64);
+    DB_IDLE_CONNECTIONS.set(state.idle_connections as i64);
+
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        error!("Failed to encode metrics: {}", e);
+    }
+    Response::new(Body::from(buffer))
+}
+
+/// Serves the Prometheus metrics over HTTP until shutdown is requested
+pub async fn serve_metrics(address: SocketAddr, pool: DbPool, shutdown: watch::Receiver<bool>) {
+    let make_service = make_service_fn(move |_| {
+        let pool = pool.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |_: Request<Body>| {
+                let response = render_metrics(&pool);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+
+    let server = match hyper::Server::try_bind(&address) {
+        Ok(builder) => builder.serve(make_service),
+        Err(e) => {
+            error!("Failed to serve metrics on {}: {}", address, e);
+            return;
+        }
+    };
+    info!("Serving metrics on {}", address);
+    if let Err(e) = server.with_graceful_shutdown(wait_for_shutdown(shutdown)).await {
+        error!("Metrics server failed: {}", e);
+    }
+}
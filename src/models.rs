@@ -1,12 +1,35 @@
-use std::ops::Deref;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use super::schema::*;
 use super::userservice::{BppUser, BppGroup, CreateBppGroup, BppRank, CreateBppRank};
 use crate::{bpp_foreign_model_impl, bpp_model_impl};
 use chrono::NaiveDateTime;
 use diesel::prelude::*;
+use log::warn;
 use prost_types::Duration;
 
+const CHANNEL_ID_PREFIX: &str = "UC";
+const CHANNEL_ID_LENGTH: usize = 24;
+
+/// Trims a YouTube channel id and checks that it looks like one
+///
+/// Channel ids are `UC` followed by 22 characters of URL-safe base64. Returns `None` for
+/// anything else.
+pub fn normalize_channel_id(channel_id: &str) -> Option<&str> {
+    let channel_id = channel_id.trim();
+    let valid = channel_id.len() == CHANNEL_ID_LENGTH
+        && channel_id.starts_with(CHANNEL_ID_PREFIX)
+        && channel_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Some(channel_id)
+    } else {
+        None
+    }
+}
+
 #[derive(Queryable, AsChangeset, Identifiable)]
 #[primary_key(rank_id)]
 #[table_name = "bpp_ranks"]
@@ -16,6 +39,7 @@ pub struct Rank {
     pub rank_sorting: i32,
     pub hour_requirement_seconds: i64,
     pub hour_requirement_nanos: i32,
+    pub payout_multiplier: f64,
 }
 
 #[derive(Insertable)]
@@ -25,6 +49,7 @@ pub struct InsertRank {
     pub rank_sorting: i32,
     pub hour_requirement_seconds: i64,
     pub hour_requirement_nanos: i32,
+    pub payout_multiplier: f64,
 }
 
 #[derive(Queryable, AsChangeset, Identifiable, PartialEq, Eq)]
@@ -55,9 +80,39 @@ pub struct User {
     pub money: f64,
     pub first_seen_at: NaiveDateTime,
     pub last_seen_at: NaiveDateTime,
+    /// Always below one second, full seconds are carried over into `hours_seconds`
+    pub hours_nanos: i32,
+    /// Set once the user was deleted, their row is only kept for history
+    pub deleted_at: Option<NaiveDateTime>,
+    /// Counts the changes made through the API, so concurrent edits can be detected
+    ///
+    /// Chat activity doesn't change it, an edit only conflicts with other edits.
+    pub version: i64,
+    /// How many chat messages the user sent
+    pub message_count: i64,
+    /// When the user was last paid money for their time
+    pub last_paid_at: Option<NaiveDateTime>,
+    /// Credited time which wasn't paid yet because the payout cooldown hadn't passed
+    pub unpaid_millis: i64,
 }
 
-#[derive(Queryable, Insertable, AsChangeset, Identifiable, Associations)]
+/// Aggregated numbers over all users
+pub struct UserStats {
+    pub total_users: i64,
+    pub recent_users: i64,
+    pub total_money: f64,
+    pub total_hours_seconds: i64,
+}
+
+/// The money in circulation among the users who weren't deleted
+pub struct EconomySummary {
+    pub total_money: f64,
+    pub average_money: f64,
+    /// The richest user, ties go to who was seen first
+    pub top_holder: Option<User>,
+}
+
+#[derive(Queryable, Insertable, AsChangeset, Identifiable, Associations, Clone)]
 #[primary_key(group_id, permission)]
 #[table_name = "bpp_groups_permissions"]
 #[belongs_to(Group, foreign_key = "group_id")]
@@ -85,16 +140,19 @@ pub struct UserPermission {
     pub channel_id: String,
     pub permission: String,
     pub granted: bool,
+    /// After this the permission doesn't count anymore, until then it lasts forever if unset
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+/// A display name a user had before, and when they stopped using it
+#[derive(Queryable, Insertable, Clone)]
+#[table_name = "bpp_users_previous_names"]
+pub struct PreviousName {
+    pub channel_id: String,
+    pub display_name: String,
+    pub replaced_at: NaiveDateTime,
 }
 
-bpp_foreign_model_impl!(
-    get_permissions_for_user,
-    UserPermission,
-    channel_id,
-    String,
-    crate::schema::bpp_users_permissions::dsl,
-    bpp_users_permissions
-);
 bpp_foreign_model_impl!(
     get_permissions_for_group,
     GroupPermission,
@@ -148,41 +206,351 @@ bpp_model_impl!(
     bpp_ranks
 );
 
-impl From<GroupPermission> for String {
-    fn from(gp: GroupPermission) -> String {
-        gp.permission
+impl GroupPermission {
+    /// Stores the permission, replacing the granted state if the group already has it
+    pub fn save(&self, conn: &diesel::PgConnection) -> QueryResult<usize> {
+        use super::schema::bpp_groups_permissions::dsl::*;
+        use diesel::pg::upsert::excluded;
+        diesel::insert_into(bpp_groups_permissions)
+            .values(self)
+            .on_conflict((group_id, permission))
+            .do_update()
+            .set(granted.eq(excluded(granted)))
+            .execute(conn)
+    }
+
+    /// Makes the stored permissions of a group match the given ones
+    ///
+    /// Permissions missing from `permissions` are deleted, new ones are inserted and the
+    /// granted state of existing ones is updated.
+    pub fn replace_for_group(
+        replace_group_id: i32,
+        permissions: &[GroupPermission],
+        conn: &diesel::PgConnection,
+    ) -> QueryResult<()> {
+        use super::schema::bpp_groups_permissions::dsl::*;
+        use diesel::pg::upsert::excluded;
+
+        let kept_permissions: Vec<&str> = permissions.iter().map(|p| p.permission.as_str()).collect();
+        diesel::delete(
+            bpp_groups_permissions
+                .filter(group_id.eq(replace_group_id))
+                .filter(permission.ne_all(kept_permissions)),
+        )
+        .execute(conn)?;
+
+        diesel::insert_into(bpp_groups_permissions)
+            .values(permissions)
+            .on_conflict((group_id, permission))
+            .do_update()
+            .set(granted.eq(excluded(granted)))
+            .execute(conn)?;
+
+        Ok(())
+    }
+}
+
+/// Whether the configured default group was missing the last time a user should have joined it
+static DEFAULT_GROUP_MISSING: AtomicBool = AtomicBool::new(false);
+
+impl GroupUser {
+    /// Makes a new user a member of the default group, if one is configured
+    ///
+    /// A default group that doesn't exist is skipped, which is only logged once until it exists.
+    pub fn add_to_default_group(
+        default_group: &str,
+        new_channel_id: &str,
+        conn: &diesel::PgConnection,
+    ) -> QueryResult<()> {
+        if default_group.is_empty() {
+            return Ok(());
+        }
+
+        let default_group_id = bpp_groups::table
+            .filter(bpp_groups::group_name.eq(default_group))
+            .order(bpp_groups::group_id.asc())
+            .select(bpp_groups::group_id)
+            .first::<i32>(conn)
+            .optional()?;
+        match default_group_id {
+            Some(default_group_id) => {
+                DEFAULT_GROUP_MISSING.store(false, Ordering::Relaxed);
+                GroupUser::add(default_group_id, new_channel_id, conn)?;
+            }
+            None => {
+                if !DEFAULT_GROUP_MISSING.swap(true, Ordering::Relaxed) {
+                    warn!("The default group \"{}\" does not exist, new users are not added to it", default_group);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the user a member of the group, doing nothing if they already are one
+    pub fn add(add_group_id: i32, add_channel_id: &str, conn: &diesel::PgConnection) -> QueryResult<usize> {
+        use super::schema::bpp_groups_users::dsl::*;
+        diesel::insert_into(bpp_groups_users)
+            .values(&GroupUser {
+                group_id: add_group_id,
+                channel_id: add_channel_id.to_string(),
+            })
+            .on_conflict_do_nothing()
+            .execute(conn)
+    }
+
+    /// Removes the user from the group
+    ///
+    /// Returns the number of removed memberships, which is 0 if the user was not a member
+    pub fn remove(remove_group_id: i32, remove_channel_id: &str, conn: &diesel::PgConnection) -> QueryResult<usize> {
+        use super::schema::bpp_groups_users::dsl::*;
+        diesel::delete(
+            bpp_groups_users
+                .filter(group_id.eq(remove_group_id))
+                .filter(channel_id.eq(remove_channel_id)),
+        )
+        .execute(conn)
     }
 }
 
-pub struct PermissionStrings(Vec<String>);
+impl PreviousName {
+    /// Records the stored display name of a user as a previous one, if it isn't `new_display_name`
+    ///
+    /// Has to run before the new name is stored. A name that was used before again only moves
+    /// its replacement time.
+    pub fn record_if_changed(
+        record_channel_id: &str,
+        new_display_name: &str,
+        changed_at: NaiveDateTime,
+        conn: &diesel::PgConnection,
+    ) -> QueryResult<usize> {
+        use super::schema::bpp_users_previous_names::dsl::*;
+        use diesel::pg::upsert::excluded;
+        use diesel::sql_types::Timestamp;
 
-impl Deref for PermissionStrings {
-    type Target = Vec<String>;
+        let replaced_name = bpp_users::table
+            .filter(bpp_users::channel_id.eq(record_channel_id))
+            .filter(bpp_users::display_name.ne(new_display_name))
+            .select((
+                bpp_users::channel_id,
+                bpp_users::display_name,
+                changed_at.into_sql::<Timestamp>(),
+            ));
+        diesel::insert_into(bpp_users_previous_names)
+            .values(replaced_name)
+            .into_columns((channel_id, display_name, replaced_at))
+            .on_conflict((channel_id, display_name))
+            .do_update()
+            .set(replaced_at.eq(excluded(replaced_at)))
+            .execute(conn)
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+    /// Loads the previous names of a user, the most recently replaced first
+    pub fn find_for_user(find_channel_id: &str, conn: &diesel::PgConnection) -> QueryResult<Vec<PreviousName>> {
+        use super::schema::bpp_users_previous_names::dsl::*;
+        bpp_users_previous_names
+            .filter(channel_id.eq(find_channel_id))
+            .order(replaced_at.desc())
+            .load(conn)
+    }
+
+    pub fn to_userservice_previous_name(&self) -> super::userservice::PreviousName {
+        super::userservice::PreviousName {
+            display_name: self.display_name.clone(),
+            replaced_at: Some(prost_types::Timestamp {
+                seconds: self.replaced_at.timestamp(),
+                nanos: self.replaced_at.timestamp_subsec_nanos() as i32,
+            }),
+        }
     }
 }
 
-impl From<Vec<GroupPermission>> for PermissionStrings {
-    fn from(permissions: Vec<GroupPermission>) -> Self {
-        let permissions: Vec<String> =
-            permissions.into_iter().map(|gp| gp.permission).collect();
-        PermissionStrings(permissions)
+impl UserPermission {
+    /// Grants a permission directly to the user, until it expires if an expiry is given
+    ///
+    /// A permission that was explicitly denied before is granted instead. Granting a permission
+    /// the user already has replaces its expiry.
+    pub fn grant(
+        grant_channel_id: &str,
+        grant_permission: &str,
+        grant_expires_at: Option<NaiveDateTime>,
+        conn: &diesel::PgConnection,
+    ) -> QueryResult<usize> {
+        use super::schema::bpp_users_permissions::dsl::*;
+        diesel::insert_into(bpp_users_permissions)
+            .values(&UserPermission {
+                channel_id: grant_channel_id.to_string(),
+                permission: grant_permission.to_string(),
+                granted: true,
+                expires_at: grant_expires_at,
+            })
+            .on_conflict((channel_id, permission))
+            .do_update()
+            .set((granted.eq(true), expires_at.eq(grant_expires_at)))
+            .execute(conn)
+    }
+
+    /// Loads the permissions given directly to users which haven't expired yet
+    ///
+    /// Expired permissions are only deleted periodically, so they have to be filtered out on
+    /// every read.
+    pub fn get_active_for_users(
+        user_channel_ids: &[&str],
+        now: NaiveDateTime,
+        conn: &diesel::PgConnection,
+    ) -> QueryResult<Vec<UserPermission>> {
+        use super::schema::bpp_users_permissions::dsl::*;
+        bpp_users_permissions
+            .filter(channel_id.eq_any(user_channel_ids))
+            .filter(expires_at.is_null().or(expires_at.gt(now)))
+            .load(conn)
+    }
+
+    /// Deletes all permissions which expired, returning how many there were
+    pub fn delete_expired(now: NaiveDateTime, conn: &diesel::PgConnection) -> QueryResult<usize> {
+        use super::schema::bpp_users_permissions::dsl::*;
+        diesel::delete(bpp_users_permissions.filter(expires_at.le(now))).execute(conn)
+    }
+
+    /// Removes a permission the user has directly
+    ///
+    /// Returns the number of removed permissions, which is 0 if the user didn't have it directly
+    pub fn remove(remove_channel_id: &str, remove_permission: &str, conn: &diesel::PgConnection) -> QueryResult<usize> {
+        use super::schema::bpp_users_permissions::dsl::*;
+        diesel::delete(
+            bpp_users_permissions
+                .filter(channel_id.eq(remove_channel_id))
+                .filter(permission.eq(remove_permission)),
+        )
+        .execute(conn)
     }
 }
 
-impl From<UserPermission> for String {
-    fn from(up: UserPermission) -> String {
-        up.permission
+/// A record of who made a change through the API
+#[derive(Queryable)]
+pub struct AuditEntry {
+    pub audit_id: i32,
+    pub actor: String,
+    pub operation: String,
+    pub target_id: Option<String>,
+    pub affected_count: i64,
+    pub created_at: NaiveDateTime,
+    /// JSON of the changed data before the change, if there was any
+    pub before_snapshot: Option<String>,
+    /// JSON of the changed data after the change, if there still is any
+    pub after_snapshot: Option<String>,
+}
+
+#[derive(Insertable)]
+#[table_name = "bpp_audit_log"]
+pub struct InsertAuditEntry<'a> {
+    pub actor: &'a str,
+    pub operation: &'a str,
+    pub target_id: Option<&'a str>,
+    pub affected_count: i64,
+    pub created_at: NaiveDateTime,
+    pub before_snapshot: Option<String>,
+    pub after_snapshot: Option<String>,
+}
+
+impl InsertAuditEntry<'_> {
+    pub fn save_to_database(&self, conn: &diesel::PgConnection) -> QueryResult<usize> {
+        diesel::insert_into(bpp_audit_log::table).values(self).execute(conn)
     }
 }
 
-impl From<Vec<UserPermission>> for PermissionStrings {
-    fn from(permissions: Vec<UserPermission>) -> Self {
-        let permissions: Vec<String> =
-            permissions.into_iter().map(|up| up.permission).collect();
-        PermissionStrings(permissions)
+impl AuditEntry {
+    /// Loads the newest entries, optionally only about one target and inside a time range
+    pub fn find(
+        find_target_id: Option<&str>,
+        from: Option<NaiveDateTime>,
+        to: Option<NaiveDateTime>,
+        limit: i64,
+        conn: &diesel::PgConnection,
+    ) -> QueryResult<Vec<AuditEntry>> {
+        use super::schema::bpp_audit_log::dsl::*;
+        let mut query = bpp_audit_log.into_boxed();
+        if let Some(find_target_id) = find_target_id {
+            query = query.filter(target_id.eq(find_target_id));
+        }
+        if let Some(from) = from {
+            query = query.filter(created_at.ge(from));
+        }
+        if let Some(to) = to {
+            query = query.filter(created_at.le(to));
+        }
+        query
+            .order((created_at.desc(), audit_id.desc()))
+            .limit(limit)
+            .load(conn)
+    }
+
+    pub fn to_userservice_entry(&self) -> super::userservice::AuditLogEntry {
+        super::userservice::AuditLogEntry {
+            audit_id: self.audit_id,
+            actor: self.actor.clone(),
+            operation: self.operation.clone(),
+            target_id: self.target_id.clone().unwrap_or_default(),
+            affected_count: self.affected_count,
+            created_at: Some(prost_types::Timestamp {
+                seconds: self.created_at.timestamp(),
+                nanos: self.created_at.timestamp_subsec_nanos() as i32,
+            }),
+            before_snapshot: self.before_snapshot.clone().unwrap_or_default(),
+            after_snapshot: self.after_snapshot.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// The last chat message whose activity was saved, so ingest can resume after it
+#[derive(Queryable, Insertable, Clone)]
+#[table_name = "bpp_ingest_position"]
+pub struct IngestPosition {
+    pub id: i32,
+    pub last_message_id: String,
+    pub last_published_at: NaiveDateTime,
+}
+
+impl IngestPosition {
+    /// Loads the stored position, which is missing until the first messages were saved
+    pub fn load(conn: &diesel::PgConnection) -> QueryResult<Option<IngestPosition>> {
+        use super::schema::bpp_ingest_position::dsl::*;
+        bpp_ingest_position.first(conn).optional()
+    }
+
+    /// Stores the given message as the last one that was saved
+    pub fn save(message_id: &str, published_at: NaiveDateTime, conn: &diesel::PgConnection) -> QueryResult<usize> {
+        use super::schema::bpp_ingest_position::dsl::*;
+        diesel::insert_into(bpp_ingest_position)
+            .values(&IngestPosition {
+                id: 1,
+                last_message_id: message_id.to_string(),
+                last_published_at: published_at,
+            })
+            .on_conflict(id)
+            .do_update()
+            .set((
+                last_message_id.eq(message_id),
+                last_published_at.eq(published_at),
+            ))
+            .execute(conn)
+    }
+
+    /// Checks if a message was published before this position, so it was already saved
+    pub fn covers(&self, message_id: &str, published_at: NaiveDateTime) -> bool {
+        published_at < self.last_published_at
+            || (published_at == self.last_published_at && message_id == self.last_message_id)
+    }
+}
+
+impl From<GroupPermission> for String {
+    fn from(gp: GroupPermission) -> String {
+        gp.permission
+    }
+}
+
+impl From<UserPermission> for String {
+    fn from(up: UserPermission) -> String {
+        up.permission
     }
 }
 
@@ -203,11 +571,185 @@ impl Ord for Group {
     }
 }
 
+impl Rank {
+    /// Loads all ranks sorted like in get_active_rank, so the first rank a user qualifies for is
+    /// theirs
+    pub fn load_by_sorting(conn: &diesel::PgConnection) -> QueryResult<Vec<Rank>> {
+        bpp_ranks::table
+            .order(bpp_ranks::rank_sorting.desc())
+            .load::<Rank>(conn)
+    }
+
+    /// Finds the rank reached with the given hours among ranks loaded with `load_by_sorting`
+    pub fn active_for_hours(ranks: &[Rank], hours_seconds: i64, hours_nanos: i32) -> Option<&Rank> {
+        ranks
+            .iter()
+            .find(|rank| rank.hour_requirement() <= (hours_seconds, hours_nanos))
+    }
+
+    /// The hour requirement as seconds and nanos, which compare like the duration they make up
+    pub fn hour_requirement(&self) -> (i64, i32) {
+        (self.hour_requirement_seconds, self.hour_requirement_nanos)
+    }
+
+    /// Saves the changed fields of a rank and returns it as it's stored now
+    ///
+    /// Returns None if the rank did not exist.
+    pub fn update(&self, conn: &diesel::PgConnection) -> QueryResult<Option<Rank>> {
+        diesel::update(bpp_ranks::table.find(self.rank_id))
+            .set(self)
+            .get_result(conn)
+            .optional()
+    }
+
+    pub fn get_by_hour_requirement(
+        check_seconds: i64,
+        check_nanos: i32,
+        conn: &diesel::PgConnection,
+    ) -> Option<Rank> {
+        use super::schema::bpp_ranks::dsl::*;
+        bpp_ranks
+            .filter(hour_requirement_seconds.eq(check_seconds))
+            .filter(hour_requirement_nanos.eq(check_nanos))
+            .first::<Rank>(conn)
+            .ok()
+    }
+
+    pub fn to_userservice_rank(&self) -> BppRank {
+        let hour_requirement = Duration {
+            seconds: self.hour_requirement_seconds,
+            nanos: self.hour_requirement_nanos,
+        };
+
+        BppRank {
+            rank_id: self.rank_id,
+            rank_name: self.rank_name.clone(),
+            rank_sorting: self.rank_sorting,
+            hour_requirement: Some(hour_requirement),
+            payout_multiplier: self.payout_multiplier,
+        }
+    }
+}
+
+impl InsertGroup {
+    /// Inserts the group, keeping the error so a name that is already taken can be reported
+    pub fn insert(&self, conn: &diesel::PgConnection) -> QueryResult<Group> {
+        diesel::insert_into(bpp_groups::table).values(self).get_result(conn)
+    }
+}
+
+impl Group {
+    /// Saves the changed fields of a group and returns it as it's stored now
+    ///
+    /// Returns None if the group did not exist.
+    pub fn update(&self, conn: &diesel::PgConnection) -> QueryResult<Option<Group>> {
+        diesel::update(bpp_groups::table.find(self.group_id))
+            .set(self)
+            .get_result(conn)
+            .optional()
+    }
+
+    /// Loads the groups a user is a member of, ordered by name
+    pub fn find_for_user(member_channel_id: &str, conn: &diesel::PgConnection) -> QueryResult<Vec<Group>> {
+        bpp_groups_users::table
+            .filter(bpp_groups_users::channel_id.eq(member_channel_id))
+            .inner_join(bpp_groups::table)
+            .select(bpp_groups::all_columns)
+            .order(bpp_groups::group_name.asc())
+            .load::<Group>(conn)
+    }
+
+    /// Loads the groups of several users at once, paired with the channel id of the member
+    pub fn find_for_users(
+        member_channel_ids: &[&str],
+        conn: &diesel::PgConnection,
+    ) -> QueryResult<Vec<(String, Group)>> {
+        bpp_groups_users::table
+            .filter(bpp_groups_users::channel_id.eq_any(member_channel_ids))
+            .inner_join(bpp_groups::table)
+            .select((bpp_groups_users::channel_id, bpp_groups::all_columns))
+            .load(conn)
+    }
+
+    /// Changes the name of a group and nothing else
+    ///
+    /// Returns None if the group did not exist.
+    pub fn rename(
+        rename_group_id: i32,
+        new_group_name: &str,
+        conn: &diesel::PgConnection,
+    ) -> QueryResult<Option<Group>> {
+        use super::schema::bpp_groups::dsl::*;
+        diesel::update(bpp_groups.filter(group_id.eq(rename_group_id)))
+            .set(group_name.eq(new_group_name))
+            .get_result::<Group>(conn)
+            .optional()
+    }
+
+    /// Deletes a group together with its permissions and memberships
+    ///
+    /// Returns the number of deleted groups, which is 0 if the group did not exist
+    pub fn delete_from_database(delete_group_id: i32, conn: &diesel::PgConnection) -> QueryResult<usize> {
+        use super::schema::{bpp_groups, bpp_groups_permissions, bpp_groups_users};
+        conn.transaction(|| {
+            diesel::delete(
+                bpp_groups_permissions::table
+                    .filter(bpp_groups_permissions::group_id.eq(delete_group_id)),
+            )
+            .execute(conn)?;
+            diesel::delete(
+                bpp_groups_users::table.filter(bpp_groups_users::group_id.eq(delete_group_id)),
+            )
+            .execute(conn)?;
+            diesel::delete(bpp_groups::table.filter(bpp_groups::group_id.eq(delete_group_id)))
+                .execute(conn)
+        })
+    }
+
+    pub fn try_get_member_count(&self, conn: &diesel::PgConnection) -> QueryResult<i64> {
+        use super::schema::bpp_groups_users::dsl::*;
+        bpp_groups_users
+            .filter(group_id.eq(self.group_id))
+            .count()
+            .get_result(conn)
+    }
+
+    /// Builds the gRPC group with its permissions and member count
+    pub fn try_to_userservice_group(&self, conn: &diesel::PgConnection) -> QueryResult<BppGroup> {
+        use super::schema::bpp_groups_permissions::dsl::*;
+        let permissions = bpp_groups_permissions
+            .filter(group_id.eq(self.group_id))
+            .load::<GroupPermission>(conn)?;
+        Ok(self.to_userservice_group_with(permissions, self.try_get_member_count(conn)?))
+    }
+
+    /// Builds the gRPC group from already loaded permissions and member count
+    fn to_userservice_group_with(&self, permissions: Vec<GroupPermission>, member_count: i64) -> BppGroup {
+        let permissions = permissions
+            .into_iter()
+            .map(|p| super::userservice::Permission {
+                permission: p.permission,
+                granted: p.granted,
+            })
+            .collect();
+
+        BppGroup {
+            group_id: self.group_id,
+            group_name: self.group_name.clone(),
+            permissions,
+            bonus_payout: self.bonus_payout,
+            group_sorting: self.group_sorting,
+            member_count: member_count as i32,
+        }
+    }
+}
+
 impl User {
     pub fn new(
         channel_id: String,
         display_name: String,
         hours_seconds: i64,
+        hours_nanos: i32,
         money: f64,
         first_seen_at: NaiveDateTime,
         last_seen_at: NaiveDateTime,
@@ -219,34 +761,642 @@ impl User {
             money,
             first_seen_at,
             last_seen_at,
+            hours_nanos,
+            deleted_at: None,
+            version: 1,
+            message_count: 0,
+            last_paid_at: None,
+            unpaid_millis: 0,
         }
     }
 
-    pub fn check_if_exists(check_channel_id: &str, conn: &diesel::PgConnection) -> bool {
+    /// Applies the fields of a BppUser that clients are allowed to change
+    pub fn apply_update(&mut self, user: &BppUser) {
+        self.display_name = user.display_name.clone();
+        self.hours_seconds = user.hours.as_ref().map_or(0, |hours| hours.seconds);
+        self.hours_nanos = user.hours.as_ref().map_or(0, |hours| hours.nanos);
+        self.money = user.money;
+    }
+
+    /// Sets the money of all users who weren't deleted to 0, returning how many there were
+    pub fn reset_all_money(conn: &diesel::PgConnection) -> QueryResult<usize> {
+        use super::schema::bpp_users::dsl::*;
+        diesel::update(bpp_users.filter(deleted_at.is_null()))
+            .set((money.eq(0.0), version.eq(version + 1)))
+            .execute(conn)
+    }
+
+    /// Sets the hours of all users who weren't deleted to 0, returning how many there were
+    pub fn reset_all_hours(conn: &diesel::PgConnection) -> QueryResult<usize> {
+        use super::schema::bpp_users::dsl::*;
+        diesel::update(bpp_users.filter(deleted_at.is_null()))
+            .set((hours_seconds.eq(0), hours_nanos.eq(0), version.eq(version + 1)))
+            .execute(conn)
+    }
+
+    /// The watched time in hours, including the nanoseconds
+    pub fn hours(&self) -> f64 {
+        (self.hours_seconds as f64 + self.hours_nanos as f64 / 1e9) / 3600.0
+    }
+
+    /// Saves the changes of `apply_update`, unless the user was changed since `expected_version`
+    ///
+    /// Returns the saved user, or `None` if the user was changed or deleted in the meantime.
+    /// An expected version of 0 comes from clients which don't know about versions, so it's
+    /// compared against the loaded version instead.
+    pub fn save_if_version(&self, expected_version: i64, conn: &diesel::PgConnection) -> QueryResult<Option<User>> {
+        use super::schema::bpp_users::dsl::*;
+        let expected_version = if expected_version == 0 {
+            self.version
+        } else {
+            expected_version
+        };
+        diesel::update(
+            bpp_users
+                .filter(channel_id.eq(&self.channel_id))
+                .filter(deleted_at.is_null())
+                .filter(version.eq(expected_version)),
+        )
+        .set((
+            display_name.eq(&self.display_name),
+            hours_seconds.eq(self.hours_seconds),
+            hours_nanos.eq(self.hours_nanos),
+            money.eq(self.money),
+            version.eq(version + 1),
+        ))
+        .get_result(conn)
+        .optional()
+    }
+
+    /// Checks if a user exists and wasn't deleted
+    pub fn check_if_exists(check_channel_id: &str, conn: &diesel::PgConnection) -> QueryResult<bool> {
         use super::schema::bpp_users::dsl::*;
         use diesel::dsl::exists;
         use diesel::select;
-        let exists: bool = select(exists(bpp_users.filter(channel_id.eq(check_channel_id))))
+        select(exists(
+            bpp_users
+                .filter(channel_id.eq(check_channel_id))
+                .filter(deleted_at.is_null()),
+        ))
+        .get_result(conn)
+    }
+
+    /// Gets a user unless they were deleted
+    pub fn get_active(get_channel_id: &str, conn: &diesel::PgConnection) -> Option<User> {
+        User::find_active(get_channel_id, conn).ok().flatten()
+    }
+
+    /// Gets a user unless they were deleted, telling a missing user apart from a failed query
+    pub fn find_active(find_channel_id: &str, conn: &diesel::PgConnection) -> QueryResult<Option<User>> {
+        use super::schema::bpp_users::dsl::*;
+        bpp_users
+            .filter(channel_id.eq(find_channel_id))
+            .filter(deleted_at.is_null())
+            .first::<User>(conn)
+            .optional()
+    }
+
+    /// Marks users as deleted, keeping their rows
+    ///
+    /// Returns the number of users that were deleted, users deleted before aren't counted.
+    pub fn soft_delete(
+        delete_channel_ids: &[String],
+        now: NaiveDateTime,
+        conn: &diesel::PgConnection,
+    ) -> QueryResult<usize> {
+        use super::schema::bpp_users::dsl::*;
+        diesel::update(
+            bpp_users
+                .filter(channel_id.eq_any(delete_channel_ids))
+                .filter(deleted_at.is_null()),
+        )
+        .set(deleted_at.eq(now))
+        .execute(conn)
+    }
+
+    /// Creates the user if they don't exist yet, otherwise updates the display name and adds the
+    /// seen messages to the message count
+    ///
+    /// Both cases are a single statement, so two messages of a new user arriving at the same
+    /// time can't both try to insert them. Returns the stored state of the user and whether
+    /// they were just created.
+    pub fn upsert_seen(
+        seen_channel_id: &str,
+        seen_display_name: &str,
+        seen_at: NaiveDateTime,
+        seen_messages: i64,
+        conn: &diesel::PgConnection,
+    ) -> QueryResult<(User, bool)> {
+        use super::schema::bpp_users::dsl::*;
+        use diesel::dsl::sql;
+        use diesel::pg::upsert::excluded;
+        use diesel::sql_types::Bool;
+        let new_user = User {
+            message_count: seen_messages,
+            ..User::new(
+                seen_channel_id.to_string(),
+                seen_display_name.to_string(),
+                0,
+                0,
+                0 as f64,
+                seen_at,
+                seen_at,
+            )
+        };
+        diesel::insert_into(bpp_users)
+            .values(&new_user)
+            .on_conflict(channel_id)
+            .do_update()
+            .set((
+                display_name.eq(excluded(display_name)),
+                message_count.eq(message_count + seen_messages),
+            ))
+            // xmax is only 0 for rows which were inserted instead of updated
+            .returning((bpp_users::all_columns(), sql::<Bool>("xmax = 0")))
             .get_result(conn)
-            .unwrap();
-        exists
     }
 
+    /// Loads the users seen at or after `since` who weren't deleted, the most recently seen first
+    ///
+    /// A limit of 0 loads all of them.
+    pub fn get_active_since(since: NaiveDateTime, limit: i64, conn: &diesel::PgConnection) -> QueryResult<Vec<User>> {
+        use super::schema::bpp_users::dsl::*;
+        let mut query = bpp_users
+            .filter(deleted_at.is_null())
+            .filter(last_seen_at.ge(since))
+            .order((last_seen_at.desc(), channel_id.asc()))
+            .into_boxed();
+        if limit > 0 {
+            query = query.limit(limit);
+        }
+        query.load(conn)
+    }
+
+    /// Gets the most recently seen user with the given display name
+    pub fn get_by_display_name(name: &str, conn: &diesel::PgConnection) -> QueryResult<Option<User>> {
+        use super::schema::bpp_users::dsl::*;
+        bpp_users
+            .filter(display_name.eq(name))
+            .filter(deleted_at.is_null())
+            .order(last_seen_at.desc())
+            .first(conn)
+            .optional()
+    }
+
+    /// Computes the user statistics in the database, without loading any users
+    ///
+    /// Users seen at or after `recent_since` count as recent users.
+    pub fn get_stats(recent_since: NaiveDateTime, conn: &diesel::PgConnection) -> QueryResult<UserStats> {
+        use super::schema::bpp_users::dsl::*;
+        use diesel::dsl::{count_star, sql};
+        use diesel::sql_types::{BigInt, Double};
+
+        let active_users = bpp_users.filter(deleted_at.is_null());
+        let total_users = active_users.select(count_star()).get_result(conn)?;
+        let recent_users = active_users
+            .filter(last_seen_at.ge(recent_since))
+            .select(count_star())
+            .get_result(conn)?;
+        // SUM of a BIGINT is a NUMERIC in Postgres, so it's cast back
+        let (total_money, total_hours_seconds) = active_users
+            .select(sql::<(Double, BigInt)>(
+                "COALESCE(SUM(money), 0), \
+                 COALESCE(SUM(hours_seconds) + SUM(hours_nanos) / 1000000000, 0)::BIGINT",
+            ))
+            .get_result(conn)?;
+
+        Ok(UserStats {
+            total_users,
+            recent_users,
+            total_money,
+            total_hours_seconds,
+        })
+    }
+
+    /// Aggregates the money of all users who weren't deleted in the database
+    pub fn get_economy_summary(conn: &diesel::PgConnection) -> QueryResult<EconomySummary> {
+        use super::schema::bpp_users::dsl::*;
+        use diesel::dsl::sql;
+        use diesel::sql_types::Double;
+
+        let active_users = bpp_users.filter(deleted_at.is_null());
+        let (total_money, average_money) = active_users
+            .select(sql::<(Double, Double)>("COALESCE(SUM(money), 0), COALESCE(AVG(money), 0)"))
+            .get_result(conn)?;
+        let top_holder = active_users
+            .order((money.desc(), first_seen_at.asc(), channel_id.asc()))
+            .first::<User>(conn)
+            .optional()?;
+
+        Ok(EconomySummary {
+            total_money,
+            average_money,
+            top_holder,
+        })
+    }
+
+    /// Inserts new users and overwrites existing ones with all of their stored values
+    ///
+    /// The imported values are the whole user, so importing a deleted user restores them, the same
+    /// way a user who doesn't exist anymore is created again. Returns the channel ids of the
+    /// inserted users and how many were updated. A channel id must only appear once.
+    pub fn import_many(users: &[User], conn: &diesel::PgConnection) -> QueryResult<(Vec<String>, usize)> {
+        use super::schema::bpp_users::dsl::*;
+        use diesel::dsl::sql;
+        use diesel::pg::upsert::excluded;
+        use diesel::sql_types::Bool;
+        let imported: Vec<(String, bool)> = diesel::insert_into(bpp_users)
+            .values(users)
+            .on_conflict(channel_id)
+            .do_update()
+            .set((
+                display_name.eq(excluded(display_name)),
+                hours_seconds.eq(excluded(hours_seconds)),
+                hours_nanos.eq(excluded(hours_nanos)),
+                money.eq(excluded(money)),
+                first_seen_at.eq(excluded(first_seen_at)),
+                last_seen_at.eq(excluded(last_seen_at)),
+                deleted_at.eq(excluded(deleted_at)),
+                message_count.eq(excluded(message_count)),
+                // An import is an edit as well, clients holding the old version have to notice it
+                version.eq(version + 1),
+            ))
+            // xmax is only 0 for rows which were inserted instead of updated
+            .returning((channel_id, sql::<Bool>("xmax = 0")))
+            .get_results(conn)?;
+
+        let updated_count = imported.iter().filter(|(_, inserted)| !*inserted).count();
+        let inserted = imported
+            .into_iter()
+            .filter(|(_, inserted)| *inserted)
+            .map(|(inserted_channel_id, _)| inserted_channel_id)
+            .collect();
+        Ok((inserted, updated_count))
+    }
+
+    /// Loads a page of the members of a group ordered by channel id, along with the number of
+    /// all members
+    ///
+    /// Deleted users aren't members anymore. A limit of 0 loads all of them.
+    pub fn get_group_members(
+        member_group_id: i32,
+        limit: i64,
+        offset: i64,
+        conn: &diesel::PgConnection,
+    ) -> QueryResult<(Vec<User>, i64)> {
+        let members = || {
+            bpp_groups_users::table
+                .filter(bpp_groups_users::group_id.eq(member_group_id))
+                .inner_join(bpp_users::table)
+                .filter(bpp_users::deleted_at.is_null())
+                .into_boxed()
+        };
+        let count = members().count().get_result(conn)?;
+
+        let mut query = members()
+            .select(bpp_users::all_columns)
+            .order(bpp_users::channel_id.asc());
+        if limit > 0 {
+            query = query.limit(limit);
+        }
+        if offset > 0 {
+            query = query.offset(offset);
+        }
+        Ok((query.load(conn)?, count))
+    }
+
+    /// Loads the next users ordered by channel id, including deleted ones
+    pub fn get_batch_after(
+        after_channel_id: &str,
+        batch_size: i64,
+        conn: &diesel::PgConnection,
+    ) -> QueryResult<Vec<User>> {
+        use super::schema::bpp_users::dsl::*;
+        bpp_users
+            .filter(channel_id.gt(after_channel_id))
+            .order(channel_id.asc())
+            .limit(batch_size)
+            .load(conn)
+    }
+
+    /// Returns which of the given users were deleted
+    pub fn find_deleted(check_channel_ids: &[&str], conn: &diesel::PgConnection) -> QueryResult<HashSet<String>> {
+        use super::schema::bpp_users::dsl::*;
+        Ok(bpp_users
+            .filter(channel_id.eq_any(check_channel_ids))
+            .filter(deleted_at.is_not_null())
+            .select(channel_id)
+            .load::<String>(conn)?
+            .into_iter()
+            .collect())
+    }
+
+    /// Loads users and locks their rows until the end of the transaction
+    ///
+    /// The rows are always locked in the order of their channel id, so two transactions locking
+    /// the same users can't deadlock.
+    pub fn get_many_for_update(lock_channel_ids: &[&str], conn: &diesel::PgConnection) -> QueryResult<Vec<User>> {
+        use super::schema::bpp_users::dsl::*;
+        bpp_users
+            .filter(channel_id.eq_any(lock_channel_ids))
+            .filter(deleted_at.is_null())
+            .order(channel_id.asc())
+            .for_update()
+            .load(conn)
+    }
+
+    /// Moves everything of `source` over to `target` and soft deletes `source`
+    ///
+    /// Hours and money are added up, and the target keeps the earliest first and the latest last
+    /// seen time. Groups and permissions the target already has stay as they are, the others are
+    /// moved, and so are the previous names. Both users have to be locked by the transaction this
+    /// runs in.
+    pub fn merge(source: &User, target: &User, now: NaiveDateTime, conn: &diesel::PgConnection) -> QueryResult<User> {
+        let memberships: Vec<GroupUser> = bpp_groups_users::table
+            .filter(bpp_groups_users::channel_id.eq(&source.channel_id))
+            .load(conn)?;
+        let memberships: Vec<GroupUser> = memberships
+            .into_iter()
+            .map(|membership| GroupUser {
+                group_id: membership.group_id,
+                channel_id: target.channel_id.clone(),
+            })
+            .collect();
+        diesel::insert_into(bpp_groups_users::table)
+            .values(&memberships)
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+        diesel::delete(bpp_groups_users::table.filter(bpp_groups_users::channel_id.eq(&source.channel_id)))
+            .execute(conn)?;
+
+        let permissions: Vec<UserPermission> = bpp_users_permissions::table
+            .filter(bpp_users_permissions::channel_id.eq(&source.channel_id))
+            .load(conn)?;
+        let permissions: Vec<UserPermission> = permissions
+            .into_iter()
+            .map(|permission| UserPermission {
+                channel_id: target.channel_id.clone(),
+                ..permission
+            })
+            .collect();
+        diesel::insert_into(bpp_users_permissions::table)
+            .values(&permissions)
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+        diesel::delete(
+            bpp_users_permissions::table.filter(bpp_users_permissions::channel_id.eq(&source.channel_id)),
+        )
+        .execute(conn)?;
+
+        let previous_names: Vec<PreviousName> = bpp_users_previous_names::table
+            .filter(bpp_users_previous_names::channel_id.eq(&source.channel_id))
+            .load(conn)?;
+        let previous_names: Vec<PreviousName> = previous_names
+            .into_iter()
+            .map(|previous_name| PreviousName {
+                channel_id: target.channel_id.clone(),
+                ..previous_name
+            })
+            .collect();
+        diesel::insert_into(bpp_users_previous_names::table)
+            .values(&previous_names)
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+        diesel::delete(
+            bpp_users_previous_names::table.filter(bpp_users_previous_names::channel_id.eq(&source.channel_id)),
+        )
+        .execute(conn)?;
+
+        User::soft_delete(std::slice::from_ref(&source.channel_id), now, conn)?;
+
+        let mut merged_seconds = target.hours_seconds + source.hours_seconds;
+        let mut merged_nanos = target.hours_nanos + source.hours_nanos;
+        if merged_nanos >= 1_000_000_000 {
+            merged_seconds += 1;
+            merged_nanos -= 1_000_000_000;
+        }
+        use super::schema::bpp_users::dsl::*;
+        diesel::update(bpp_users.filter(channel_id.eq(&target.channel_id)))
+            .set((
+                hours_seconds.eq(merged_seconds),
+                hours_nanos.eq(merged_nanos),
+                money.eq(target.money + source.money),
+                message_count.eq(target.message_count + source.message_count),
+                first_seen_at.eq(target.first_seen_at.min(source.first_seen_at)),
+                last_seen_at.eq(target.last_seen_at.max(source.last_seen_at)),
+                version.eq(version + 1),
+            ))
+            .get_result(conn)
+    }
+
+    /// Adds `delta` to the money of a user in a single statement, so concurrent adjustments add up
+    ///
+    /// Returns `None` if the user doesn't exist or the adjustment would make their money negative.
+    pub fn adjust_money(adjust_channel_id: &str, delta: f64, conn: &diesel::PgConnection) -> QueryResult<Option<User>> {
+        use super::schema::bpp_users::dsl::*;
+        diesel::update(
+            bpp_users
+                .filter(channel_id.eq(adjust_channel_id))
+                .filter(deleted_at.is_null())
+                .filter((money + delta).ge(0.0)),
+        )
+        .set((money.eq(money + delta), version.eq(version + 1)))
+        .get_result(conn)
+        .optional()
+    }
+
+    /// Inserts new users and updates existing ones in a single statement
+    pub fn upsert_many(users: &[User], conn: &diesel::PgConnection) -> QueryResult<usize> {
+        use super::schema::bpp_users::dsl::*;
+        use diesel::pg::upsert::excluded;
+        diesel::insert_into(bpp_users)
+            .values(users)
+            .on_conflict(channel_id)
+            .do_update()
+            .set((
+                display_name.eq(excluded(display_name)),
+                hours_seconds.eq(excluded(hours_seconds)),
+                hours_nanos.eq(excluded(hours_nanos)),
+                money.eq(excluded(money)),
+                last_seen_at.eq(excluded(last_seen_at)),
+                last_paid_at.eq(excluded(last_paid_at)),
+                unpaid_millis.eq(excluded(unpaid_millis)),
+            ))
+            .execute(conn)
+    }
+
+    /// Erases a user together with their group memberships and permissions
+    ///
+    /// Returns the number of deleted users, which is 0 if the user did not exist
+    pub fn delete_from_database(delete_channel_id: &str, conn: &diesel::PgConnection) -> QueryResult<usize> {
+        use super::schema::{bpp_groups_users, bpp_users, bpp_users_permissions, bpp_users_previous_names};
+        conn.transaction(|| {
+            diesel::delete(
+                bpp_users_previous_names::table.filter(bpp_users_previous_names::channel_id.eq(delete_channel_id)),
+            )
+            .execute(conn)?;
+            diesel::delete(
+                bpp_groups_users::table.filter(bpp_groups_users::channel_id.eq(delete_channel_id)),
+            )
+            .execute(conn)?;
+            diesel::delete(
+                bpp_users_permissions::table
+                    .filter(bpp_users_permissions::channel_id.eq(delete_channel_id)),
+            )
+            .execute(conn)?;
+            diesel::delete(bpp_users::table.filter(bpp_users::channel_id.eq(delete_channel_id)))
+                .execute(conn)
+        })
+    }
+
+    /// Gets the rank the user qualifies for with their current hours
+    ///
+    /// Ranks are never stored on the user, they're derived from the current rank table every
+    /// time, so changed thresholds apply to everyone immediately.
     pub fn get_active_rank(&self, conn: &diesel::PgConnection) -> Option<Rank> {
+        self.find_active_rank(conn).ok().flatten()
+    }
+
+    /// Like `get_active_rank`, but returns query errors instead of treating them as no rank
+    pub fn find_active_rank(&self, conn: &diesel::PgConnection) -> QueryResult<Option<Rank>> {
         use super::schema::bpp_ranks::dsl::*;
 
         // Get all ranks which match the hour requirements and sort by the sorting field
-        let rank: Option<Rank> = bpp_ranks
-            .filter(hour_requirement_seconds.le(self.hours_seconds))
+        bpp_ranks
+            .filter(
+                hour_requirement_seconds.lt(self.hours_seconds).or(hour_requirement_seconds
+                    .eq(self.hours_seconds)
+                    .and(hour_requirement_nanos.le(self.hours_nanos))),
+            )
             .order(rank_sorting.desc())
-            .first::<Rank>(conn).ok();
-        rank
+            .first::<Rank>(conn)
+            .optional()
     }
 
-    pub fn to_userservice_user(&self, conn: &diesel::PgConnection) -> BppUser {
+    /// Finds the rank the user reaches next after their current one
+    ///
+    /// That's the rank with the lowest requirement among those sorted above the current rank, or
+    /// among all ranks while the user has none.
+    pub fn find_next_rank(
+        &self,
+        current: Option<&Rank>,
+        conn: &diesel::PgConnection,
+    ) -> QueryResult<Option<Rank>> {
+        use super::schema::bpp_ranks::dsl::*;
+        let mut query = bpp_ranks.into_boxed();
+        if let Some(current) = current {
+            query = query.filter(rank_sorting.gt(current.rank_sorting));
+        }
+        query
+            .order((hour_requirement_seconds.asc(), hour_requirement_nanos.asc()))
+            .first::<Rank>(conn)
+            .optional()
+    }
+
+    /// Builds the gRPC user with its groups, permissions and rank
+    pub fn try_to_userservice_user(&self, conn: &diesel::PgConnection) -> QueryResult<BppUser> {
+        let groups = bpp_groups_users::table
+            .filter(bpp_groups_users::channel_id.eq(&self.channel_id))
+            .inner_join(bpp_groups::table)
+            .select(bpp_groups::all_columns)
+            .load::<Group>(conn)?;
+        let now = chrono::Utc::now().naive_utc();
+        let permissions = UserPermission::get_active_for_users(&[&self.channel_id], now, conn)?;
+        let groups = groups
+            .iter()
+            .map(|group| group.try_to_userservice_group(conn))
+            .collect::<QueryResult<Vec<BppGroup>>>()?;
+        let rank = self.find_active_rank(conn)?;
+
+        Ok(self.to_userservice_user_with(groups, permissions, rank.as_ref()))
+    }
+
+    /// Builds the gRPC users of many users with a fixed number of queries
+    ///
+    /// The groups, permissions and ranks of all users are loaded at once and assigned in memory,
+    /// instead of querying them for every single user.
+    pub fn to_userservice_users(users: &[User], conn: &diesel::PgConnection) -> QueryResult<Vec<BppUser>> {
+        use diesel::dsl::sql;
+        use diesel::sql_types::BigInt;
+
+        if users.is_empty() {
+            return Ok(Vec::new());
+        }
+        let channel_ids: Vec<&str> = users.iter().map(|user| user.channel_id.as_str()).collect();
+
+        let memberships: Vec<(String, Group)> = bpp_groups_users::table
+            .filter(bpp_groups_users::channel_id.eq_any(&channel_ids))
+            .inner_join(bpp_groups::table)
+            .select((bpp_groups_users::channel_id, bpp_groups::all_columns))
+            .load(conn)?;
+        let mut group_ids: Vec<i32> = memberships.iter().map(|(_, group)| group.group_id).collect();
+        group_ids.sort_unstable();
+        group_ids.dedup();
+
+        let mut group_permissions: HashMap<i32, Vec<GroupPermission>> = HashMap::new();
+        for permission in bpp_groups_permissions::table
+            .filter(bpp_groups_permissions::group_id.eq_any(&group_ids))
+            .load::<GroupPermission>(conn)?
+        {
+            group_permissions.entry(permission.group_id).or_default().push(permission);
+        }
+        let member_counts: HashMap<i32, i64> = bpp_groups_users::table
+            .filter(bpp_groups_users::group_id.eq_any(&group_ids))
+            .group_by(bpp_groups_users::group_id)
+            .select((bpp_groups_users::group_id, sql::<BigInt>("count(*)")))
+            .load::<(i32, i64)>(conn)?
+            .into_iter()
+            .collect();
+        let mut groups: HashMap<String, Vec<BppGroup>> = HashMap::new();
+        for (member_channel_id, group) in memberships {
+            let permissions = group_permissions.get(&group.group_id).cloned().unwrap_or_default();
+            let member_count = member_counts.get(&group.group_id).copied().unwrap_or(0);
+            groups
+                .entry(member_channel_id)
+                .or_default()
+                .push(group.to_userservice_group_with(permissions, member_count));
+        }
+
+        let mut permissions: HashMap<String, Vec<UserPermission>> = HashMap::new();
+        let now = chrono::Utc::now().naive_utc();
+        for permission in UserPermission::get_active_for_users(&channel_ids, now, conn)? {
+            permissions.entry(permission.channel_id.clone()).or_default().push(permission);
+        }
+
+        // Sorted like in find_active_rank, so the first rank a user qualifies for is theirs
+        let ranks = bpp_ranks::table
+            .order(bpp_ranks::rank_sorting.desc())
+            .load::<Rank>(conn)?;
+
+        Ok(users
+            .iter()
+            .map(|user| {
+                let rank = Rank::active_for_hours(&ranks, user.hours_seconds, user.hours_nanos);
+                user.to_userservice_user_with(
+                    groups.remove(&user.channel_id).unwrap_or_default(),
+                    permissions.remove(&user.channel_id).unwrap_or_default(),
+                    rank,
+                )
+            })
+            .collect())
+    }
+
+    /// Builds the gRPC user from the columns of the user alone, without groups, permissions and
+    /// rank, so it needs no queries
+    pub fn to_basic_userservice_user(&self) -> BppUser {
+        self.to_userservice_user_with(Vec::new(), Vec::new(), None)
+    }
+
+    /// Builds the gRPC user from its already loaded groups, permissions and rank
+    fn to_userservice_user_with(
+        &self,
+        groups: Vec<BppGroup>,
+        permissions: Vec<UserPermission>,
+        rank: Option<&Rank>,
+    ) -> BppUser {
         let prost_duration = prost_types::Duration {
             seconds: self.hours_seconds,
-            nanos: 0,
+            nanos: self.hours_nanos,
         };
 
         let first_seen_at_ts = prost_types::Timestamp {
@@ -258,43 +1408,24 @@ impl User {
             nanos: self.last_seen_at.timestamp_subsec_nanos() as i32,
         };
 
-        let groups = Group::get_groups_for_user(self.channel_id.clone(), conn);
-        let permissions =
-            UserPermission::get_permissions_for_user(self.channel_id.clone(), conn);
         let permissions: Vec<super::userservice::Permission> = permissions.into_iter()
             .map(|p|super::userservice::Permission {
                 permission: p.permission,
                 granted: p.granted,
             })
             .collect();
-        let groups = groups
-            .iter()
-            .map(|group| {
-                let permissions =
-                    GroupPermission::get_permissions_for_group(group.group_id, conn);
-                let permissions = permissions.into_iter()
-                    .map(|p|super::userservice::Permission {
-                        permission: p.permission,
-                        granted: p.granted,
-                    })
-                    .collect();
-
-                super::userservice::BppGroup {
-                    group_id: group.group_id,
-                    group_name: group.group_name.clone(),
-                    permissions,
-                    bonus_payout: group.bonus_payout,
-                    group_sorting: group.group_sorting,
-                }
-            })
-            .collect::<Vec<super::userservice::BppGroup>>();
 
-        let rank = if let Some(rank) = self.get_active_rank(conn) {
-            rank.rank_name
+        let rank = if let Some(rank) = rank {
+            rank.rank_name.clone()
         } else {
             "default".to_string()
         };
 
+        let deleted_at = self.deleted_at.map(|deleted_at| prost_types::Timestamp {
+            seconds: deleted_at.timestamp(),
+            nanos: deleted_at.timestamp_subsec_nanos() as i32,
+        });
+
         BppUser {
             channel_id: self.channel_id.clone(),
             display_name: self.display_name.clone(),
@@ -304,7 +1435,13 @@ impl User {
             last_seen_at: Some(last_seen_at_ts),
             groups,
             permissions,
-            rank
+            rank,
+            deleted_at,
+            version: self.version,
+            hours_decimal: self.hours(),
+            minutes_decimal: self.hours() * 60.0,
+            message_count: self.message_count,
+            previous_names: Vec::new(),
         }
     }
 }
@@ -317,6 +1454,7 @@ impl From<CreateBppRank> for InsertRank {
             hour_requirement_seconds: requirement.seconds,
             hour_requirement_nanos: requirement.nanos,
             rank_sorting: rank.rank_sorting,
+            payout_multiplier: rank.payout_multiplier,
         }
     }
 }
@@ -330,6 +1468,7 @@ impl From<BppRank> for Rank {
             hour_requirement_seconds: requirement.seconds,
             hour_requirement_nanos: requirement.nanos,
             rank_sorting: br.rank_sorting,
+            payout_multiplier: br.payout_multiplier,
         }
     }
 }
@@ -342,6 +1481,7 @@ impl From<&BppRank> for Rank {
             hour_requirement_seconds: br.hour_requirement.as_ref().unwrap().seconds,
             hour_requirement_nanos: br.hour_requirement.as_ref().unwrap().nanos,
             rank_sorting: br.rank_sorting,
+            payout_multiplier: br.payout_multiplier,
         }
     }
 }
@@ -378,27 +1518,32 @@ impl From<&BppGroup> for Group {
     }
 }
 
+/// Converts the seen timestamps of a BppUser, filling in a missing one from the other
+///
+/// Users that were never seen at all count as seen right now.
+fn seen_timestamps(
+    first_seen_at: &Option<prost_types::Timestamp>,
+    last_seen_at: &Option<prost_types::Timestamp>,
+) -> (NaiveDateTime, NaiveDateTime) {
+    let to_naive = |timestamp: &Option<prost_types::Timestamp>| {
+        timestamp
+            .as_ref()
+            .and_then(|t| NaiveDateTime::from_timestamp_opt(t.seconds, t.nanos as u32))
+    };
+    match (to_naive(first_seen_at), to_naive(last_seen_at)) {
+        (Some(first), Some(last)) => (first, last),
+        (Some(first), None) => (first, first),
+        (None, Some(last)) => (last, last),
+        (None, None) => {
+            let now = chrono::Utc::now().naive_utc();
+            (now, now)
+        }
+    }
+}
+
 impl From<BppUser> for User {
     fn from(user: BppUser) -> User {
-        let hours = user.hours.unwrap_or(Duration {
-            seconds: 0,
-            nanos: 0,
-        });
-        let first_seen_at = user.first_seen_at.unwrap();
-        let last_seen_at = user.last_seen_at.unwrap();
-
-        let first_seen_at_naive =
-            NaiveDateTime::from_timestamp(first_seen_at.seconds, first_seen_at.nanos as u32);
-        let last_seen_at_naive =
-            NaiveDateTime::from_timestamp(last_seen_at.seconds, last_seen_at.nanos as u32);
-        User {
-            channel_id: user.channel_id,
-            display_name: user.display_name,
-            hours_seconds: hours.seconds,
-            money: user.money,
-            first_seen_at: first_seen_at_naive,
-            last_seen_at: last_seen_at_naive,
-        }
+        User::from(&user)
     }
 }
 
@@ -408,20 +1553,21 @@ impl From<&BppUser> for User {
             seconds: 0,
             nanos: 0,
         });
-        let first_seen_at = user.first_seen_at.clone().unwrap();
-        let last_seen_at = user.last_seen_at.clone().unwrap();
+        let (first_seen_at, last_seen_at) = seen_timestamps(&user.first_seen_at, &user.last_seen_at);
 
-        let first_seen_at_naive =
-            NaiveDateTime::from_timestamp(first_seen_at.seconds, first_seen_at.nanos as u32);
-        let last_seen_at_naive =
-            NaiveDateTime::from_timestamp(last_seen_at.seconds, last_seen_at.nanos as u32);
         User {
             channel_id: user.channel_id.clone(),
             display_name: user.display_name.clone(),
             hours_seconds: hours.seconds,
             money: user.money,
-            first_seen_at: first_seen_at_naive,
-            last_seen_at: last_seen_at_naive,
+            first_seen_at,
+            last_seen_at,
+            hours_nanos: hours.nanos,
+            deleted_at: None,
+            version: user.version.max(1),
+            message_count: user.message_count.max(0),
+            last_paid_at: None,
+            unpaid_millis: 0,
         }
     }
 }
@@ -0,0 +1,354 @@
+use std::collections::BTreeSet;
+
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::PgConnection;
+use log::info;
+
+use crate::auth::rpc_permissions;
+use crate::models::{Group, GroupPermission, UserPermission};
+use crate::schema::bpp_groups_permissions;
+
+/// The permissions which can be stored, so a typo is rejected instead of never matching
+///
+/// These are the permissions of the RPCs and the configured ones of the other services.
+pub struct PermissionRegistry {
+    permissions: BTreeSet<String>,
+}
+
+impl PermissionRegistry {
+    pub fn new(configured: &[String]) -> PermissionRegistry {
+        let mut permissions: BTreeSet<String> = rpc_permissions().map(str::to_string).collect();
+        permissions.extend(configured.iter().map(|permission| permission.trim().to_string()));
+        permissions.remove("");
+        PermissionRegistry { permissions }
+    }
+
+    /// Returns the known permissions in alphabetical order
+    pub fn permissions(&self) -> impl Iterator<Item = &str> {
+        self.permissions.iter().map(String::as_str)
+    }
+
+    /// Checks if a permission can be stored
+    ///
+    /// Negated permissions are checked without the negation, and a wildcard is known as long as
+    /// its namespace contains a known permission.
+    pub fn is_known(&self, stored: &str) -> bool {
+        let (permission, _) = parse_stored_permission(stored, true);
+        if permission == "*" || self.permissions.contains(permission) {
+            return true;
+        }
+        match permission.strip_suffix('*') {
+            Some(namespace) if namespace.ends_with('.') => {
+                self.permissions.iter().any(|known| known.starts_with(namespace))
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Checks if a stored permission applies to the requested permission
+///
+/// A stored permission ending in `.*` applies to everything inside of that namespace,
+/// so `bpp.*` matches `bpp.users.delete`, but not `bpp` itself.
+pub fn permission_matches(stored: &str, requested: &str) -> bool {
+    if stored == requested || stored == "*" {
+        return true;
+    }
+
+    match stored.strip_suffix('*') {
+        Some(namespace) if namespace.ends_with('.') => requested.starts_with(namespace),
+        _ => false,
+    }
+}
+
+/// Splits the negation off a stored permission
+///
+/// A permission stored as `-bpp.economy.gamble` denies `bpp.economy.gamble`, no matter how it's
+/// flagged, so exceptions can be carved out of a broader wildcard grant.
+pub fn parse_stored_permission(stored: &str, granted: bool) -> (&str, bool) {
+    match stored.strip_prefix('-') {
+        Some(negated) => (negated, false),
+        None => (stored, granted),
+    }
+}
+
+/// Returns the granted state of the most specific stored permission that matches the requested one
+///
+/// An exact match always wins over wildcards, otherwise the longest wildcard wins. If a grant
+/// and a denial are equally specific, the denial wins.
+pub fn most_specific_match<'a, I>(permissions: I, requested: &str) -> Option<bool>
+where
+    I: IntoIterator<Item = (&'a str, bool)>,
+{
+    permissions
+        .into_iter()
+        .map(|(stored, granted)| parse_stored_permission(stored, granted))
+        .filter(|(stored, _)| permission_matches(stored, requested))
+        .max_by_key(|(stored, granted)| {
+            let specificity = if *stored == requested {
+                usize::MAX
+            } else {
+                stored.len()
+            };
+            (specificity, !granted)
+        })
+        .map(|(_, granted)| granted)
+}
+
+/// Where a permission of a user comes from
+#[derive(Clone)]
+pub enum PermissionSource {
+    Direct,
+    Group { group_id: i32, group_name: String },
+}
+
+/// The permissions given to a user by one group, or directly
+struct PermissionLayer {
+    source: PermissionSource,
+    /// The sorting of the group, direct permissions have none and outrank every group
+    priority: Option<i32>,
+    permissions: Vec<(String, bool)>,
+}
+
+/// A permission of a user after all groups and direct permissions were applied
+pub struct ResolvedPermission {
+    pub permission: String,
+    pub granted: bool,
+    pub source: PermissionSource,
+}
+
+/// Resolves the negation of a stored permission, so layers only hold plain permission names
+fn stored_permission(stored: &str, granted: bool) -> (String, bool) {
+    let (permission, granted) = parse_stored_permission(stored, granted);
+    (permission.to_string(), granted)
+}
+
+/// Loads the permission layers of a user in the order they are applied
+///
+/// Groups come first in ascending sorting order, so higher sorted groups override lower ones.
+/// Permissions given directly to the user come last and override all groups.
+fn load_permission_layers(channel_id: &str, conn: &PgConnection) -> QueryResult<Vec<PermissionLayer>> {
+    let mut layers = Vec::new();
+
+    let mut user_groups = Group::find_for_user(channel_id, conn)?;
+    user_groups.sort();
+    let group_ids: Vec<i32> = user_groups.iter().map(|group| group.group_id).collect();
+    let group_permissions: Vec<GroupPermission> = bpp_groups_permissions::table
+        .filter(bpp_groups_permissions::group_id.eq_any(group_ids))
+        .load(conn)?;
+    for group in user_groups {
+        layers.push(PermissionLayer {
+            permissions: group_permissions
+                .iter()
+                .filter(|p| p.group_id == group.group_id)
+                .map(|p| stored_permission(&p.permission, p.granted))
+                .collect(),
+            source: PermissionSource::Group {
+                group_id: group.group_id,
+                group_name: group.group_name,
+            },
+            priority: Some(group.group_sorting),
+        });
+    }
+
+    let now = Utc::now().naive_utc();
+    let user_permissions = UserPermission::get_active_for_users(&[channel_id], now, conn)?;
+    layers.push(PermissionLayer {
+        source: PermissionSource::Direct,
+        priority: None,
+        permissions: user_permissions
+            .into_iter()
+            .map(|p| stored_permission(&p.permission, p.granted))
+            .collect(),
+    });
+
+    Ok(layers)
+}
+
+/// Returns the granted state of a permission and the layer which decided it
+///
+/// If groups with the same sorting conflict, the denial wins, so the result doesn't depend on
+/// the order the groups were loaded in.
+fn resolve_in_layers<'a>(layers: &'a [PermissionLayer], permission: &str) -> Option<(bool, &'a PermissionSource)> {
+    let mut resolved: Option<(bool, &PermissionLayer)> = None;
+    for layer in layers {
+        let layer_permissions = layer.permissions.iter().map(|(p, granted)| (p.as_str(), *granted));
+        if let Some(granted) = most_specific_match(layer_permissions, permission) {
+            let tied_with_denial = matches!(
+                resolved,
+                Some((false, resolved_layer)) if resolved_layer.priority == layer.priority
+            );
+            if !tied_with_denial {
+                resolved = Some((granted, layer));
+            }
+        }
+    }
+
+    resolved.map(|(granted, layer)| (granted, &layer.source))
+}
+
+/// Resolves if a user has a permission
+///
+/// Groups are applied in ascending sorting order, so higher sorted groups override lower ones,
+/// which makes the sorting the priority of a group. Permissions given directly to the user
+/// override all groups.
+pub fn resolve_user_permission(
+    channel_id: &str,
+    permission: &str,
+    granted_default: bool,
+    conn: &PgConnection,
+) -> QueryResult<bool> {
+    let layers = load_permission_layers(channel_id, conn)?;
+    Ok(resolve_in_layers(&layers, permission).map_or(granted_default, |(granted, _)| granted))
+}
+
+/// Lists every permission stored for a user or one of their groups, resolved the same way as
+/// [`resolve_user_permission`], together with the group or direct permission which decided it
+pub fn list_user_permissions(channel_id: &str, conn: &PgConnection) -> QueryResult<Vec<ResolvedPermission>> {
+    let layers = load_permission_layers(channel_id, conn)?;
+
+    let mut permissions: Vec<&str> = layers
+        .iter()
+        .flat_map(|layer| layer.permissions.iter().map(|(p, _)| p.as_str()))
+        .collect();
+    permissions.sort_unstable();
+    permissions.dedup();
+
+    Ok(permissions
+        .into_iter()
+        .filter_map(|permission| {
+            let (granted, source) = resolve_in_layers(&layers, permission)?;
+            Some(ResolvedPermission {
+                permission: permission.to_string(),
+                granted,
+                source: source.clone(),
+            })
+        })
+        .collect())
+}
+
+/// Deletes permissions which expired, meant to run periodically
+///
+/// Expired permissions already don't count before they're deleted, this only keeps the table
+/// from growing.
+pub fn remove_expired_permissions(conn: &PgConnection) -> Result<(), Box<dyn std::error::Error>> {
+    let removed = UserPermission::delete_expired(Utc::now().naive_utc(), conn)?;
+    if removed > 0 {
+        info!("Removed {} expired permissions", removed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards_match_inside_their_namespace() {
+        assert!(permission_matches("bpp.*", "bpp.users.delete"));
+        assert!(permission_matches("*", "bpp"));
+        assert!(!permission_matches("bpp.*", "bpp"));
+        assert!(!permission_matches("bpp*", "bpp.users"));
+    }
+
+    #[test]
+    fn exact_match_beats_wildcard() {
+        let permissions = vec![("bpp.*", true), ("bpp.users.delete", false)];
+        assert_eq!(most_specific_match(permissions, "bpp.users.delete"), Some(false));
+        let permissions = vec![("bpp.users.delete", true), ("bpp.users.*", false)];
+        assert_eq!(most_specific_match(permissions, "bpp.users.delete"), Some(true));
+    }
+
+    #[test]
+    fn longest_wildcard_wins() {
+        let permissions = vec![("*", false), ("bpp.users.*", true), ("bpp.*", false)];
+        assert_eq!(most_specific_match(permissions, "bpp.users.delete"), Some(true));
+        assert_eq!(most_specific_match(vec![("bpp.*", true)], "other"), None);
+    }
+
+    #[test]
+    fn denial_wins_a_tie() {
+        let permissions = vec![("bpp.*", true), ("bpp.*", false)];
+        assert_eq!(most_specific_match(permissions, "bpp.users"), Some(false));
+        let permissions = vec![("bpp.users", false), ("bpp.users", true)];
+        assert_eq!(most_specific_match(permissions, "bpp.users"), Some(false));
+    }
+
+    #[test]
+    fn negation_denies_regardless_of_the_flag() {
+        assert_eq!(parse_stored_permission("-bpp.economy.gamble", true), ("bpp.economy.gamble", false));
+        assert_eq!(parse_stored_permission("bpp.economy.gamble", true), ("bpp.economy.gamble", true));
+        let permissions = vec![("bpp.economy.*", true), ("-bpp.economy.gamble", true)];
+        assert_eq!(most_specific_match(permissions, "bpp.economy.gamble"), Some(false));
+        assert_eq!(most_specific_match(vec![("-bpp.economy.*", true)], "bpp.economy.pay"), Some(false));
+    }
+
+    fn group_layer(group_id: i32, sorting: i32, permissions: &[(&str, bool)]) -> PermissionLayer {
+        PermissionLayer {
+            source: PermissionSource::Group {
+                group_id,
+                group_name: format!("group {}", group_id),
+            },
+            priority: Some(sorting),
+            permissions: permissions
+                .iter()
+                .map(|(permission, granted)| stored_permission(permission, *granted))
+                .collect(),
+        }
+    }
+
+    fn direct_layer(permissions: &[(&str, bool)]) -> PermissionLayer {
+        PermissionLayer {
+            source: PermissionSource::Direct,
+            priority: None,
+            permissions: permissions
+                .iter()
+                .map(|(permission, granted)| stored_permission(permission, *granted))
+                .collect(),
+        }
+    }
+
+    fn resolved_group(layers: &[PermissionLayer], permission: &str) -> Option<(bool, Option<i32>)> {
+        resolve_in_layers(layers, permission).map(|(granted, source)| match source {
+            PermissionSource::Direct => (granted, None),
+            PermissionSource::Group { group_id, .. } => (granted, Some(*group_id)),
+        })
+    }
+
+    #[test]
+    fn higher_sorted_groups_override_lower_ones() {
+        let layers = vec![
+            group_layer(1, 1, &[("bpp.economy.*", false)]),
+            group_layer(2, 5, &[("bpp.economy.*", true)]),
+            direct_layer(&[]),
+        ];
+        assert_eq!(resolved_group(&layers, "bpp.economy.pay"), Some((true, Some(2))));
+        assert_eq!(resolved_group(&layers, "bpp.users"), None);
+    }
+
+    #[test]
+    fn denial_wins_between_groups_with_the_same_sorting() {
+        let layers = vec![
+            group_layer(1, 3, &[("bpp.economy.pay", false)]),
+            group_layer(2, 3, &[("bpp.economy.pay", true)]),
+            direct_layer(&[]),
+        ];
+        assert_eq!(resolved_group(&layers, "bpp.economy.pay"), Some((false, Some(1))));
+    }
+
+    #[test]
+    fn direct_permissions_override_groups() {
+        let layers = vec![
+            group_layer(1, 10, &[("bpp.economy.pay", false)]),
+            direct_layer(&[("bpp.economy.pay", true)]),
+        ];
+        assert_eq!(resolved_group(&layers, "bpp.economy.pay"), Some((true, None)));
+        let layers = vec![
+            group_layer(1, 10, &[("bpp.*", true)]),
+            direct_layer(&[("-bpp.economy.*", true)]),
+        ];
+        assert_eq!(resolved_group(&layers, "bpp.economy.pay"), Some((false, None)));
+        assert_eq!(resolved_group(&layers, "bpp.users"), Some((true, Some(1))));
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use tonic::Request;
+
+/// How many clients are tracked before the buckets which refilled completely are dropped
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// Limits how many requests each client can make with a token bucket per client
+///
+/// Every request takes a token, the buckets hold up to `burst` tokens and refill with
+/// `per_second` tokens per second.
+pub struct RateLimiter {
+    burst: f64,
+    per_second: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Creates the limiter, which lets every request through if `per_second` is 0
+    pub fn new(burst: u32, per_second: u32) -> RateLimiter {
+        RateLimiter {
+            burst: burst.max(1) as f64,
+            per_second: per_second as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from the bucket of a client, returns false if it's empty
+    pub fn try_acquire(&self, client: &str) -> bool {
+        if self.per_second <= 0.0 {
+            return true;
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !buckets.contains_key(client) && buckets.len() >= MAX_TRACKED_CLIENTS {
+            // A full bucket behaves exactly like a missing one, so forgetting it changes nothing
+            let (burst, per_second) = (self.burst, self.per_second);
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated_at).as_secs_f64() * per_second < burst
+            });
+        }
+
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated_at: now,
+        });
+        let refilled = now.duration_since(bucket.updated_at).as_secs_f64() * self.per_second;
+        bucket.tokens = (bucket.tokens + refilled).min(self.burst);
+        bucket.updated_at = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+/// Names the client a request is counted for
+///
+/// That's the IP address of the peer, so a client's connections share a bucket. Without one, as
+/// behind some proxies, the bearer token identifies the client.
+pub fn client_of<T>(request: &Request<T>) -> String {
+    if let Some(address) = request.remote_addr() {
+        return address.ip().to_string();
+    }
+    request
+        .metadata()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .map(|token| format!("token:{}", token))
+        .unwrap_or_else(|| "unknown".to_string())
+}
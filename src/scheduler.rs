@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use diesel::PgConnection;
+use log::{debug, error, info};
+use tokio::sync::watch;
+
+use crate::shutdown::wait_for_shutdown;
+use crate::DbPool;
+
+/// A unit of periodic work, which gets a database connection for every run
+pub type JobFn = fn(&PgConnection) -> Result<(), Box<dyn std::error::Error>>;
+
+struct Job {
+    name: &'static str,
+    interval: Duration,
+    run: JobFn,
+}
+
+/// Runs registered jobs on their own intervals until shutdown is requested
+///
+/// Every job runs in its own task and on the blocking thread pool, so a job that fails, panics
+/// or takes long doesn't affect the other jobs or the runtime.
+#[derive(Default)]
+pub struct Scheduler {
+    jobs: Vec<Job>,
+}
+
+impl Scheduler {
+    /// Registers a job to run every `interval`, the first run happens one interval after startup
+    pub fn register(&mut self, name: &'static str, interval: Duration, run: JobFn) {
+        self.jobs.push(Job { name, interval, run });
+    }
+
+    /// Runs all registered jobs and resolves once they stopped after shutdown was requested
+    pub async fn run(self, pool: DbPool, shutdown: watch::Receiver<bool>) {
+        let handles: Vec<_> = self
+            .jobs
+            .into_iter()
+            .map(|job| tokio::spawn(run_job(job, pool.clone(), shutdown.clone())))
+            .collect();
+        for handle in handles {
+            let _ = handle.await;
+        }
+    }
+}
+
+async fn run_job(job: Job, pool: DbPool, shutdown: watch::Receiver<bool>) {
+    info!("Running job {} every {}s", job.name, job.interval.as_secs());
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(job.interval) => {}
+            _ = wait_for_shutdown(shutdown.clone()) => return,
+        }
+
+        debug!("Running job {}", job.name);
+        let pool = pool.clone();
+        let run = job.run;
+        let result = tokio::task::spawn_blocking(move || {
+            let conn = pool.get().map_err(|e| e.to_string())?;
+            run(&conn).map_err(|e| e.to_string())
+        })
+        .await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("Job {} failed: {}", job.name, e),
+            Err(e) => error!("Job {} panicked: {}", job.name, e),
+        }
+    }
+}
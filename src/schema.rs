@@ -1,3 +1,16 @@
+table! {
+    bpp_audit_log (audit_id) {
+        audit_id -> Int4,
+        actor -> Varchar,
+        operation -> Varchar,
+        target_id -> Nullable<Varchar>,
+        affected_count -> Int8,
+        created_at -> Timestamp,
+        before_snapshot -> Nullable<Text>,
+        after_snapshot -> Nullable<Text>,
+    }
+}
+
 table! {
     bpp_groups (group_id) {
         group_id -> Int4,
@@ -22,6 +35,14 @@ table! {
     }
 }
 
+table! {
+    bpp_ingest_position (id) {
+        id -> Int4,
+        last_message_id -> Varchar,
+        last_published_at -> Timestamp,
+    }
+}
+
 table! {
     bpp_ranks (rank_id) {
         rank_id -> Int4,
@@ -29,6 +50,7 @@ table! {
         rank_sorting -> Int4,
         hour_requirement_seconds -> Int8,
         hour_requirement_nanos -> Int4,
+        payout_multiplier -> Float8,
     }
 }
 
@@ -40,6 +62,12 @@ table! {
         money -> Float8,
         first_seen_at -> Timestamp,
         last_seen_at -> Timestamp,
+        hours_nanos -> Int4,
+        deleted_at -> Nullable<Timestamp>,
+        version -> Int8,
+        message_count -> Int8,
+        last_paid_at -> Nullable<Timestamp>,
+        unpaid_millis -> Int8,
     }
 }
 
@@ -48,6 +76,15 @@ table! {
         channel_id -> Varchar,
         permission -> Varchar,
         granted -> Bool,
+        expires_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    bpp_users_previous_names (channel_id, display_name) {
+        channel_id -> Varchar,
+        display_name -> Varchar,
+        replaced_at -> Timestamp,
     }
 }
 
@@ -55,12 +92,16 @@ joinable!(bpp_groups_permissions -> bpp_groups (group_id));
 joinable!(bpp_groups_users -> bpp_groups (group_id));
 joinable!(bpp_groups_users -> bpp_users (channel_id));
 joinable!(bpp_users_permissions -> bpp_users (channel_id));
+joinable!(bpp_users_previous_names -> bpp_users (channel_id));
 
 allow_tables_to_appear_in_same_query!(
+    bpp_audit_log,
     bpp_groups,
     bpp_groups_permissions,
     bpp_groups_users,
+    bpp_ingest_position,
     bpp_ranks,
     bpp_users,
     bpp_users_permissions,
+    bpp_users_previous_names,
 );
@@ -1,3 +1,6 @@
+// tonic::Status is large, but it's the error of every handler and of the helpers they share
+#![allow(clippy::result_large_err)]
+
 #[macro_use]
 extern crate diesel;
 #[macro_use]
@@ -5,36 +8,75 @@ extern crate diesel_migrations;
 #[macro_use]
 extern crate serde;
 
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::env;
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 
-use ::log::{debug, error, info};
-use chrono::NaiveDateTime;
-use chrono::Utc;
+use ::log::{debug, error, info, warn};
+use chrono::{NaiveDateTime, Utc};
+use diesel::connection::SimpleConnection;
 use diesel::prelude::*;
 use diesel::r2d2::ConnectionManager;
 use diesel::PgConnection;
 use diesel_migrations::embed_migrations;
 use dotenv::dotenv;
-use models::{Group, GroupPermission, InsertGroup, InsertRank, User, UserPermission, Rank};
-use r2d2::Pool;
+use models::{normalize_channel_id, AuditEntry, Group, GroupPermission, GroupUser, InsertGroup, InsertRank, PreviousName, User, Rank};
+use r2d2::{Pool, PooledConnection};
+use tonic::transport::{Certificate, Channel, Endpoint, Identity, ServerTlsConfig};
 use tonic::Response;
+use tonic::Streaming;
 use tonic::Status;
-use tonic::transport::Channel;
+use tokio::sync::{broadcast, watch};
+use tokio_stream::Stream;
 use tonic::Request;
 
 use userservice::user_service_server::{UserService, UserServiceServer};
-use userservice::{BppGroup, BppUser};
+use userservice::{BppGroup, BppUser, RankUpEvent};
 use youtubeservice::you_tube_service_client::YouTubeServiceClient;
 
-use crate::log::setup_log;
+use crate::audit::{
+    actor_of, group_snapshot, group_with_permissions_snapshot, rank_snapshot, user_snapshot, AuditChange,
+};
+use crate::auth::{authorize_caller, TokenAuth};
+use crate::database::{application_name, ConnectionSettings};
+use crate::filters::{
+    after_cursor_expression, decode_channel_id_cursor, encode_channel_id_cursor, encode_cursor, filter_users_query,
+    request_sort_keys, sort_field_names, sort_users_query, FILTER_NAMES,
+};
+use crate::health::report_health;
+use crate::ingest::ingest_messages;
+use crate::log::{setup_log, LogFormat};
+use crate::metrics::{record_request, serve_metrics, PoolMetrics};
+use crate::ratelimit::{client_of, RateLimiter};
+use crate::permissions::{
+    list_user_permissions, remove_expired_permissions, resolve_user_permission, PermissionRegistry, PermissionSource,
+};
+use crate::scheduler::Scheduler;
 use crate::settings::Settings;
+use crate::shutdown::{listen_for_shutdown, wait_for_shutdown};
+use crate::telemetry::{record_channel_id, request_span, setup_tracing, shutdown_tracing};
 
 mod settings;
+mod audit;
+mod auth;
+mod database;
+mod filters;
+mod health;
+mod ingest;
 mod log;
 mod macros;
+mod metrics;
 mod models;
+mod permissions;
+mod ratelimit;
+mod scheduler;
 mod schema;
+mod shutdown;
+mod telemetry;
 
 embed_migrations!();
 
@@ -48,102 +90,514 @@ pub mod userservice {
 
 type Void = Result<(), Box<dyn std::error::Error>>;
 type DbPool = Pool<ConnectionManager<PgConnection>>;
-
-pub fn connect_to_database() -> Pool<ConnectionManager<PgConnection>> {
+type DbConnection = PooledConnection<ConnectionManager<PgConnection>>;
+
+const DATABASE_POOL_SIZE: u32 = 10;
+const DEFAULT_AUDIT_LOG_LIMIT: i64 = 100;
+const MAX_AUDIT_LOG_LIMIT: i64 = 1000;
+const CONCURRENT_UPDATE_MESSAGE: &str = "The user was changed since it was loaded, reload it and try again";
+/// How many rank-ups a subscriber may fall behind before it starts missing them
+const RANK_UP_CHANNEL_CAPACITY: usize = 64;
+const DEFAULT_EXPORT_BATCH_SIZE: i64 = 500;
+const MAX_EXPORT_BATCH_SIZE: i64 = 5000;
+const IMPORT_BATCH_SIZE: usize = 500;
+const DEFAULT_LEADERBOARD_LIMIT: i64 = 10;
+const MAX_LEADERBOARD_LIMIT: i64 = 100;
+
+/// Creates the connection pool, running the pending migrations first if `run_migrations` is set
+pub fn connect_to_database(
+    connection_timeout: std::time::Duration,
+    connection_settings: ConnectionSettings,
+    run_migrations: bool,
+) -> Result<DbPool, Box<dyn std::error::Error>> {
     // Get the database URL from the environment
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let database_url = env::var("DATABASE_URL").map_err(|_| "DATABASE_URL must be set")?;
     let manager = ConnectionManager::new(database_url);
-    // Create a connection pool of 10 connections
-    let pool = Pool::builder().max_size(10).build(manager).unwrap();
+    let statement_timeout_ms = connection_settings.statement_timeout_ms;
+    let pool = Pool::builder()
+        .max_size(DATABASE_POOL_SIZE)
+        .connection_timeout(connection_timeout)
+        .connection_customizer(Box::new(connection_settings))
+        .event_handler(Box::new(PoolMetrics))
+        .build(manager)
+        .map_err(|e| format!("failed to connect to the database: {}", e))?;
+    if run_migrations {
+        migrate_database(&pool, statement_timeout_ms)?;
+    }
+
+    Ok(pool)
+}
+
+/// Runs the pending migrations, without the statement timeout of the pooled connections
+fn migrate_database(pool: &DbPool, statement_timeout_ms: u32) -> Void {
+    let conn = pool
+        .get()
+        .map_err(|e| format!("failed to get a connection for migrations: {}", e))?;
+    conn.batch_execute("SET statement_timeout = 0")
+        .map_err(|e| format!("failed to prepare the connection for migrations: {}", e))?;
+    let result = embedded_migrations::run_with_output(&conn, &mut std::io::stdout())
+        .map_err(|e| format!("failed to run migrations: {}", e));
+    // The connection goes back to the pool, so it gets the timeout the others have
+    conn.batch_execute(&format!("SET statement_timeout = {}", statement_timeout_ms))
+        .map_err(|e| format!("failed to reset the statement timeout: {}", e))?;
+    result?;
+
+    Ok(())
+}
 
-    // Run migrations
-    let _ = embedded_migrations::run_with_output(&pool.get().unwrap(), &mut std::io::stdout());
+/// Replaces the password of a connection URL, so it can be logged
+fn redact_url(url: &str) -> String {
+    let credentials_start = url.find("://").map_or(0, |index| index + 3);
+    let credentials_end = match url[credentials_start..].find('@') {
+        Some(index) => credentials_start + index,
+        None => return url.to_string(),
+    };
+    match url[credentials_start..credentials_end].find(':') {
+        Some(index) => format!(
+            "{}:***{}",
+            &url[..credentials_start + index],
+            &url[credentials_end..]
+        ),
+        None => url.to_string(),
+    }
+}
 
-    pool
+/// Everything about the loaded configuration that's worth knowing when reading the logs
+struct StartupSummary<'a> {
+    listen_address: SocketAddr,
+    metrics_address: SocketAddr,
+    youtube_addresses: &'a str,
+    settings: &'a Settings,
+    tls_enabled: bool,
+    token_auth_enabled: bool,
+    authorize_callers: bool,
 }
 
-fn calculate_hours_and_money(user: &mut User, now: &NaiveDateTime, settings: Settings, conn: &PgConnection) {
-    let new_hours_seconds;
-    let hours_duration = chrono::Duration::seconds(user.hours_seconds);
-    let new_duration = *now - user.last_seen_at;
-    debug!("Between the last time the user was seen and now, {} seconds have passed", new_duration.num_seconds());
-    let hours = hours_duration + new_duration;
-    new_hours_seconds = hours.num_seconds();
-    debug!(
-        "Updating hours of {} ({}) from {}s to {}s",
-        user.channel_id,
-        user.display_name,
-        user.hours_seconds,
-        new_hours_seconds
+/// Logs the resolved configuration, without any secrets
+fn log_startup_summary(summary: &StartupSummary) {
+    let database_url = env::var("DATABASE_URL").map_or_else(|_| "<unset>".to_string(), |url| redact_url(&url));
+    info!("Configuration:");
+    info!("  listen address: {}", summary.listen_address);
+    info!("  metrics address: {}", summary.metrics_address);
+    info!("  database: {} ({} connections)", database_url, DATABASE_POOL_SIZE);
+    info!("  youtubeservice: {}", summary.youtube_addresses);
+    info!("  active window: {}s", summary.settings.active_time);
+    info!("  money per hour: {}", summary.settings.default_payout);
+    info!("  member multiplier: {}", summary.settings.member_multiplier);
+    info!("  configured permissions: {}", summary.settings.known_permissions.len());
+    info!("  previous names: {}", summary.settings.track_previous_names);
+    if !summary.settings.default_group.is_empty() {
+        info!("  default group: {}", summary.settings.default_group);
+    }
+    info!(
+        "  payouts: every {}s, chat required: {}",
+        summary.settings.payout_cooldown_seconds, summary.settings.money_requires_chat
     );
+    info!("  message buffer: {}ms", summary.settings.message_buffer_ms);
+    info!("  minimum message length: {}", summary.settings.min_message_length);
+    info!("  dry run: {}", summary.settings.dry_run);
+    info!("  TLS: {}", summary.tls_enabled);
+    info!("  token authentication: {}", summary.token_auth_enabled);
+    info!("  caller authorization: {}", summary.authorize_callers);
+}
 
-    user.hours_seconds = new_hours_seconds;
+/// Creates a channel balancing over a comma separated list of youtubeservice addresses
+///
+/// The channel connects lazily, so an instance being down at startup isn't fatal. Requests
+/// are only sent to instances that are reachable.
+fn youtube_channel(addresses: &str) -> Result<Channel, Box<dyn std::error::Error>> {
+    let endpoints = addresses
+        .split(',')
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .map(|address| {
+            Endpoint::from_shared(address.to_string())
+                .map_err(|e| format!("invalid youtubeservice address {}: {}", address, e))
+        })
+        .collect::<Result<Vec<Endpoint>, String>>()?;
+    if endpoints.is_empty() {
+        return Err("YTS_GRPC_ADDRESS must contain at least one address".into());
+    }
+
+    Ok(Channel::balance_list(endpoints.into_iter()))
+}
 
-    // Grant x money per minute
-    let mut money_per_minute: f64 = settings.default_payout as f64;
-    let user_groups = Group::get_groups_for_user(user.channel_id.clone(), conn);
-    for group in user_groups {
-        money_per_minute += group.bonus_payout as f64;
+/// Reads the TLS configuration of the server from the environment
+///
+/// Returns `None` if `TLS_CERT_PATH` and `TLS_KEY_PATH` aren't set, so the server runs in
+/// plaintext. If `TLS_CLIENT_CA_PATH` is set as well, clients need a certificate signed by it.
+fn tls_config_from_env() -> Result<Option<ServerTlsConfig>, Box<dyn std::error::Error>> {
+    let non_empty_var = |name| env::var(name).ok().filter(|value: &String| !value.is_empty());
+    let (cert_path, key_path) = match (non_empty_var("TLS_CERT_PATH"), non_empty_var("TLS_KEY_PATH")) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        (None, None) => return Ok(None),
+        _ => return Err("TLS_CERT_PATH and TLS_KEY_PATH must be set together".into()),
+    };
+
+    let cert = std::fs::read(&cert_path)
+        .map_err(|e| format!("failed to read TLS certificate {}: {}", cert_path, e))?;
+    let key = std::fs::read(&key_path)
+        .map_err(|e| format!("failed to read TLS key {}: {}", key_path, e))?;
+    let mut tls_config = ServerTlsConfig::new().identity(Identity::from_pem(cert, key));
+
+    if let Some(ca_path) = non_empty_var("TLS_CLIENT_CA_PATH") {
+        let ca = std::fs::read(&ca_path)
+            .map_err(|e| format!("failed to read TLS client CA {}: {}", ca_path, e))?;
+        tls_config = tls_config.client_ca_root(Certificate::from_pem(ca));
+        info!("Clients need a certificate signed by {}", ca_path);
     }
-    let money_per_second: f64 = money_per_minute / 60.0;
 
-    let new_money = user.money + money_per_second * new_duration.num_seconds() as f64;
-    debug!(
-        "Updating money of {} ({}) from {:.2} to {:.2}",
-        user.channel_id, user.display_name, user.money, new_money
-    );
-    user.money = new_money;
+    Ok(Some(tls_config))
 }
 
-async fn fetch_users_from_messages(
-    youtube_client: &mut YouTubeServiceClient<Channel>,
-    pool: &DbPool,
-) -> Void {
-    let mut stream = youtube_client
-        .subscribe_messages(Request::new(()))
-        .await?
-        .into_inner();
-
-    while let Some(message) = stream.message().await? {
-        let conn = pool.get()?;
-        let now = Utc::now().naive_utc();
-        let mut user = if User::check_if_exists(&message.channel_id, &conn) {
-            debug!("Updating existing user {}", &message.channel_id);
-            // Update the user
-            User::get_from_database(&message.channel_id, &conn).unwrap()
-        } else {
-            debug!("Creating new user {}", &message.channel_id);
-            // Create the user
-            User::new(
-                message.channel_id.clone(),
-                message.display_name.clone(),
-                0,
-                0 as f64,
-                now,
-                now,
-            )
-        };
+/// Checks that an update doesn't set negative hours or money
+fn validate_user_update(user: &BppUser) -> Result<(), Status> {
+    let hours_seconds = user.hours.as_ref().map_or(0, |hours| hours.seconds);
+    if hours_seconds < 0 || user.money < 0.0 {
+        return Err(Status::invalid_argument(format!(
+            "Hours and money of {} must not be negative",
+            user.channel_id
+        )));
+    }
+    let hours_nanos = user.hours.as_ref().map_or(0, |hours| hours.nanos);
+    if !(0..1_000_000_000).contains(&hours_nanos) {
+        return Err(Status::invalid_argument(format!(
+            "The nanoseconds of the hours of {} must be between 0 and 999999999",
+            user.channel_id
+        )));
+    }
 
-        user.display_name = message.display_name.clone();
+    Ok(())
+}
 
-        let settings = Settings::new()?;
+/// Turns an imported user into the one to store, or the reason why it can't be imported
+///
+/// Imported ids are stored like the ones from ingest, or the same user could exist twice.
+fn import_row(user: &BppUser) -> Result<User, String> {
+    let channel_id = match normalize_channel_id(&user.channel_id) {
+        Some(channel_id) => channel_id.to_string(),
+        None => return Err("The channel id is not a valid YouTube channel id".to_string()),
+    };
+    validate_user_update(user).map_err(|status| status.message().to_string())?;
+    let mut db_user = User::from(user);
+    db_user.channel_id = channel_id;
+    Ok(db_user)
+}
+
+/// Checks that a rank has a non-negative hour requirement and a multiplier of at least 1
+fn validate_rank(
+    hour_requirement: &Option<prost_types::Duration>,
+    payout_multiplier: f64,
+) -> Result<(), Status> {
+    match hour_requirement {
+        Some(requirement) if requirement.seconds >= 0 && requirement.nanos >= 0 => {}
+        Some(_) => {
+            return Err(Status::invalid_argument(
+                "Hour requirement must not be negative",
+            ))
+        }
+        None => return Err(Status::invalid_argument("Hour requirement is required")),
+    }
+    if payout_multiplier.is_nan() || payout_multiplier < 1.0 {
+        return Err(Status::invalid_argument(
+            "Payout multiplier must be at least 1.0",
+        ));
+    }
+
+    Ok(())
+}
 
-        // Determine if user was active before this message and if so, update the hours
-        // if the user has been last seen less than the configured timeframe, update the hours
-        if user.last_seen_at + chrono::Duration::seconds(settings.active_time as i64) > now {
-            calculate_hours_and_money(&mut user, &now, settings, &conn);
+/// Runs blocking database work on the blocking threads of the runtime, so it can't stall other
+/// requests or the ingest
+///
+/// Fails with DEADLINE_EXCEEDED once the work took longer than `timeout`. Blocking work can't be
+/// interrupted, so the connection it holds only returns to the pool when its query ends.
+async fn run_blocking<T, F>(timeout: std::time::Duration, work: F) -> Result<T, Status>
+where
+    F: FnOnce() -> Result<T, Status> + Send + 'static,
+    T: Send + 'static,
+{
+    // The span of the request has to be carried over to the thread the work runs on
+    let span = tracing::Span::current();
+    let work = tokio::task::spawn_blocking(move || span.in_scope(work));
+    match tokio::time::timeout(timeout, work).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => {
+            error!("Database work failed: {}", e);
+            Err(Status::internal("The request failed"))
         }
-        user.last_seen_at = now;
+        Err(_) => Err(Status::deadline_exceeded("The database didn't respond in time")),
+    }
+}
 
-        // Update the user
-        user.save_to_database(&conn).unwrap();
+/// Loads the next batch of an export, giving the connection back right after
+fn load_export_batch(pool: &DbPool, after_channel_id: &str, batch_size: i64) -> Result<Vec<BppUser>, Status> {
+    let conn = pool.get().map_err(|e| {
+        error!("Failed to get a database connection: {}", e);
+        Status::unavailable("database busy")
+    })?;
+    let users = User::get_batch_after(after_channel_id, batch_size, &conn).map_err(|e| {
+        error!("{}", e);
+        Status::internal("Failed to export users")
+    })?;
+
+    User::to_userservice_users(&users, &conn).map_err(|e| {
+        error!("{}", e);
+        Status::internal("Failed to export users")
+    })
+}
+
+/// Attaches the cursor to resume an interrupted export as the `next-cursor` metadata of a status
+fn with_resume_cursor(mut status: Status, after_channel_id: &str) -> Status {
+    if let Ok(cursor) = encode_channel_id_cursor(after_channel_id).parse() {
+        status.metadata_mut().insert("next-cursor", cursor);
     }
+    status
+}
 
+/// Converts an optional bound of an audit log request
+fn audit_timestamp(timestamp: Option<prost_types::Timestamp>) -> Result<Option<NaiveDateTime>, Status> {
+    match timestamp {
+        Some(timestamp) => NaiveDateTime::from_timestamp_opt(timestamp.seconds, timestamp.nanos as u32)
+            .map(Some)
+            .ok_or_else(|| Status::invalid_argument("Invalid timestamp")),
+        None => Ok(None),
+    }
+}
+
+/// Checks if a user exists and wasn't deleted, logging the query error if that can't be checked
+fn user_exists(channel_id: &str, conn: &PgConnection) -> Result<bool, Status> {
+    User::check_if_exists(channel_id, conn).map_err(|e| {
+        error!("{}", e);
+        Status::internal("Failed to look up the user")
+    })
+}
+
+/// Checks that both the user and the group of a membership exist
+fn validate_membership(membership: &userservice::GroupMembership, conn: &PgConnection) -> Result<(), Status> {
+    if !user_exists(&membership.channel_id, conn)? {
+        return Err(Status::not_found("User not found"));
+    }
+    if Group::get_from_database(&membership.group_id, conn).is_none() {
+        return Err(Status::not_found("Group not found"));
+    }
     Ok(())
 }
 
+/// Builds the gRPC user, logging the query error if its groups, permissions or rank can't be loaded
+fn userservice_user(user: &User, conn: &PgConnection) -> Result<BppUser, Status> {
+    user.try_to_userservice_user(conn).map_err(|e| {
+        error!("{}", e);
+        Status::internal("Failed to load the groups, permissions or rank of user")
+    })
+}
+
+/// Builds the gRPC group, logging the query error if its permissions or members can't be loaded
+fn userservice_group(group: &Group, conn: &PgConnection) -> Result<BppGroup, Status> {
+    group.try_to_userservice_group(conn).map_err(|e| {
+        error!("{}", e);
+        Status::internal("Failed to load the permissions or members of group")
+    })
+}
+
+#[derive(Clone)]
 pub struct UserServer {
-    database_pool: DbPool
+    database_pool: DbPool,
+    /// How long an RPC waits for its database work before giving up
+    database_timeout: std::time::Duration,
+    rank_ups: broadcast::Sender<RankUpEvent>,
+    shutdown: watch::Receiver<bool>,
+    /// Whether callers of mutating RPCs need the permission for it
+    authorize_callers: bool,
+    /// Limits the read RPCs, which can be expensive, per client
+    rate_limiter: Arc<RateLimiter>,
+    /// The group every created user joins, none if empty
+    default_group: String,
+    permission_registry: Arc<PermissionRegistry>,
+    /// How long ago users could have been seen to count as active, unless a request asks otherwise
+    active_window: chrono::Duration,
+}
+
+/// Checks the name of a group which is created or changed, taken names are rejected by the database
+fn validate_group_name(group_name: &str) -> Result<(), Status> {
+    if group_name.trim().is_empty() {
+        return Err(Status::invalid_argument("Group name must not be empty"));
+    }
+    Ok(())
+}
+
+/// Checks if a query failed because a unique constraint, like the one on group names, was violated
+fn is_unique_violation(error: &diesel::result::Error) -> bool {
+    matches!(
+        error,
+        diesel::result::Error::DatabaseError(diesel::result::DatabaseErrorKind::UniqueViolation, _)
+    )
+}
+
+/// Updates a group, reconciles its permissions with the given ones and records the change
+///
+/// Meant to run inside a transaction. Returns None if the group doesn't exist.
+fn save_group_with_permissions(
+    actor: &str,
+    operation: &str,
+    group: &BppGroup,
+    conn: &PgConnection,
+) -> QueryResult<Option<Group>> {
+    let before = match Group::get_from_database(&group.group_id, conn) {
+        Some(before) => before,
+        None => return Ok(None),
+    };
+    let before_permissions = GroupPermission::get_permissions_for_group(before.group_id, conn)?;
+
+    let db_permissions: Vec<GroupPermission> = group
+        .permissions
+        .iter()
+        .map(|p| GroupPermission {
+            group_id: group.group_id,
+            permission: p.permission.clone(),
+            granted: p.granted,
+        })
+        .collect();
+    let db_group = match Group::from(group).update(conn)? {
+        Some(db_group) => db_group,
+        None => return Ok(None),
+    };
+    GroupPermission::replace_for_group(db_group.group_id, &db_permissions, conn)?;
+    AuditChange::new(actor, operation)
+        .target(db_group.group_id)
+        .before(group_with_permissions_snapshot(&before, &before_permissions))
+        .after(group_with_permissions_snapshot(&db_group, &db_permissions))
+        .save(conn)?;
+    Ok(Some(db_group))
+}
+
+/// Updates a rank unless it would share its hour requirement with another rank, and records the
+/// change
+///
+/// Meant to run inside a transaction. Returns the status to reject the update with if the rank
+/// doesn't exist or collides with another one.
+fn save_rank(actor: &str, operation: &str, rank: &Rank, conn: &PgConnection) -> QueryResult<Result<Rank, Status>> {
+    let before = match Rank::get_from_database(&rank.rank_id, conn) {
+        Some(before) => before,
+        None => return Ok(Err(Status::not_found(format!("Rank {} not found", rank.rank_id)))),
+    };
+    let colliding_rank = Rank::get_by_hour_requirement(rank.hour_requirement_seconds, rank.hour_requirement_nanos, conn);
+    if matches!(colliding_rank, Some(other) if other.rank_id != rank.rank_id) {
+        return Ok(Err(Status::already_exists("A rank with this hour requirement already exists")));
+    }
+
+    let db_rank = match rank.update(conn)? {
+        Some(db_rank) => db_rank,
+        None => return Ok(Err(Status::not_found(format!("Rank {} not found", rank.rank_id)))),
+    };
+    AuditChange::new(actor, operation)
+        .target(db_rank.rank_id)
+        .before(rank_snapshot(&before))
+        .after(rank_snapshot(&db_rank))
+        .save(conn)?;
+    Ok(Ok(db_rank))
+}
+
+impl UserServer {
+    /// Gets a connection from the pool, or UNAVAILABLE if the pool is exhausted
+    fn conn(&self) -> Result<DbConnection, Status> {
+        self.database_pool.get().map_err(|e| {
+            error!("Failed to get a database connection: {}", e);
+            Status::unavailable("database busy")
+        })
+    }
+
+    /// Runs a bulk reset and records it in the audit log in the same transaction
+    fn reset_all<T>(
+        &self,
+        operation: &str,
+        confirmation: &str,
+        request: &Request<T>,
+        reset: fn(&diesel::PgConnection) -> diesel::QueryResult<usize>,
+    ) -> Result<userservice::ResetResult, Status> {
+        let expected_confirmation = operation.replace('_', " ").to_uppercase();
+        if confirmation != expected_confirmation {
+            return Err(Status::failed_precondition(format!(
+                "The confirmation has to be \"{}\"",
+                expected_confirmation
+            )));
+        }
+        let actor = actor_of(request);
+        let conn = self.conn()?;
+
+        let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+            let affected = reset(&conn)?;
+            AuditChange::new(&actor, operation)
+                .affected(affected as i64)
+                .save(&conn)?;
+            Ok(affected)
+        });
+        match result {
+            Ok(affected) => {
+                warn!("{} ran {} on {} users", actor, operation, affected);
+                Ok(userservice::ResetResult {
+                    affected_users: affected as i64,
+                })
+            }
+            Err(e) => {
+                error!("{}", e);
+                Err(Status::internal(format!("Failed to run {}", operation)))
+            }
+        }
+    }
+
+    /// Rejects the request if the caller lacks the permission the RPC requires
+    fn authorize<T>(&self, method: &str, request: &Request<T>) -> Result<(), Status> {
+        if !self.authorize_callers {
+            return Ok(());
+        }
+        let conn = self.conn()?;
+        authorize_caller(method, request, &conn)
+    }
+
+    /// Fails with RESOURCE_EXHAUSTED if the client of a request used up its rate limit
+    fn limit_rate<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        if self.rate_limiter.try_acquire(&client_of(request)) {
+            return Ok(());
+        }
+        Err(Status::resource_exhausted("Too many requests, try again later"))
+    }
+
+    /// Grants or denies a permission to a group and records it in the audit log in one transaction
+    fn save_group_permission(&self, actor: &str, operation: &str, db_permission: &GroupPermission) -> Result<(), Status> {
+        let conn = self.conn()?;
+        let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+            db_permission.save(&conn)?;
+            AuditChange::new(actor, operation)
+                .target(db_permission.group_id)
+                .after(serde_json::json!({
+                    "permission": db_permission.permission,
+                    "granted": db_permission.granted,
+                }))
+                .save(&conn)
+        });
+        match result {
+            Ok(_) => Ok(()),
+            Err(diesel::result::Error::DatabaseError(diesel::result::DatabaseErrorKind::ForeignKeyViolation, _)) => {
+                Err(Status::not_found("Group not found"))
+            }
+            Err(e) => {
+                error!("{}", e);
+                Err(Status::internal("Failed to save the permission of the group"))
+            }
+        }
+    }
+
+    /// Fails with INVALID_ARGUMENT if a permission isn't in the registry
+    fn validate_permissions<'a, I>(&self, permissions: I) -> Result<(), Status>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        match permissions.into_iter().find(|permission| !self.permission_registry.is_known(permission)) {
+            Some(unknown) => Err(Status::invalid_argument(format!("Unknown permission {}", unknown))),
+            None => Ok(()),
+        }
+    }
 }
 
 #[tonic::async_trait]
@@ -152,516 +606,2039 @@ impl UserService for UserServer {
         &self,
         request: tonic::Request<String>,
     ) -> Result<tonic::Response<userservice::BppUser>, tonic::Status> {
-        let user_id = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        let potential_user = User::get_from_database(&user_id, &conn);
+        self.limit_rate(&request)?;
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            let user_id = request.into_inner();
+            record_channel_id(&user_id);
+            let conn = server.conn()?;
+            let user = match User::find_active(&user_id, &conn) {
+                Ok(Some(user)) => user,
+                Ok(None) => return Err(tonic::Status::not_found("User not found")),
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(Status::internal("Failed to load user"));
+                }
+            };
+
+            let mut bpp_user = userservice_user(&user, &conn)?;
+            bpp_user.previous_names = match PreviousName::find_for_user(&user.channel_id, &conn) {
+                Ok(previous_names) => previous_names.iter().map(PreviousName::to_userservice_previous_name).collect(),
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(Status::internal("Failed to load the previous names of user"));
+                }
+            };
+            Ok(tonic::Response::new(bpp_user))
+        })
+        .await
+    }
 
-        match potential_user {
-            Some(user) => {
-                let bpp_user = user.to_userservice_user(&conn);
-                return Ok(tonic::Response::new(bpp_user));
+    async fn get_user_basic(
+        &self,
+        request: tonic::Request<String>,
+    ) -> Result<tonic::Response<userservice::BppUser>, tonic::Status> {
+        self.limit_rate(&request)?;
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            let user_id = request.into_inner();
+            record_channel_id(&user_id);
+            let conn = server.conn()?;
+            match User::find_active(&user_id, &conn) {
+                Ok(Some(user)) => Ok(tonic::Response::new(user.to_basic_userservice_user())),
+                Ok(None) => Err(tonic::Status::not_found("User not found")),
+                Err(e) => {
+                    error!("{}", e);
+                    Err(Status::internal("Failed to load user"))
+                }
             }
-            None => Err(tonic::Status::not_found("User not found")),
-        }
+        })
+        .await
+    }
+
+    async fn get_user_by_name(
+        &self,
+        request: tonic::Request<String>,
+    ) -> Result<tonic::Response<userservice::BppUser>, tonic::Status> {
+        self.limit_rate(&request)?;
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            let name = request.into_inner();
+            let conn = server.conn()?;
+
+            // Display names aren't unique, the user who chatted most recently wins
+            match User::get_by_display_name(&name, &conn) {
+                Ok(Some(user)) => {
+                    let bpp_user = userservice_user(&user, &conn)?;
+                    Ok(tonic::Response::new(bpp_user))
+                }
+                Ok(None) => Err(tonic::Status::not_found("User not found")),
+                Err(e) => {
+                    error!("{}", e);
+                    Err(tonic::Status::internal("Failed to get user"))
+                }
+            }
+        })
+        .await
     }
 
     async fn filter_users(
         &self,
         request: tonic::Request<userservice::BppUserFilters>,
     ) -> Result<tonic::Response<userservice::BppUsers>, tonic::Status> {
-        let filter_request = request.into_inner();
-        let filters = &filter_request.filters;
-        let conn = self.database_pool.get().unwrap();
-
-        use schema::bpp_users::dsl::*;
-        let mut query = bpp_users.into_boxed();
-        for filter in filters {
-            let inner_filter = filter.filter.as_ref().unwrap();
-            match inner_filter {
-                userservice::bpp_user_filter::Filter::ChannelId(filter_channel_id) => {
-                    query = query.filter(channel_id.eq(filter_channel_id));
+        self.limit_rate(&request)?;
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            let filter_request = request.into_inner();
+            let sort_keys = request_sort_keys(&filter_request);
+            let after_cursor = if filter_request.cursor.is_empty() {
+                None
+            } else {
+                if filter_request.offset > 0 {
+                    return Err(Status::invalid_argument("A cursor can't be combined with an offset"));
                 }
-                userservice::bpp_user_filter::Filter::Name(filter_name) => {
-                    query = query.filter(display_name.eq(filter_name));
+                match after_cursor_expression(&filter_request.cursor, &sort_keys) {
+                    Some(after_cursor) => Some(after_cursor),
+                    None => return Err(Status::invalid_argument("Invalid cursor for these sort keys")),
                 }
-                userservice::bpp_user_filter::Filter::Hours(filter_hours) => {
-                    query = query.filter(hours_seconds.eq(filter_hours));
+            };
+            let conn = server.conn()?;
+
+            // Everything is read from one snapshot, so the count always matches the returned users
+            let mut failed_to = "load ranks";
+            let transaction = conn.build_transaction().repeatable_read().read_only();
+            let result = transaction.run::<_, diesel::result::Error, _>(|| {
+                let ranks = schema::bpp_ranks::table.load::<Rank>(&conn)?;
+
+                // The count covers all matching users, not just the requested page
+                failed_to = "count users";
+                let count: i64 = filter_users_query(&filter_request, &ranks).count().get_result(&conn)?;
+
+                let mut query = filter_users_query(&filter_request, &ranks);
+                if let Some(after_cursor) = after_cursor {
+                    query = query.filter(after_cursor);
                 }
-                userservice::bpp_user_filter::Filter::Money(filter_money) => {
-                    query = query.filter(money.eq(filter_money));
+                if filter_request.limit > 0 {
+                    query = query.limit(filter_request.limit);
+                }
+                if filter_request.offset > 0 {
+                    query = query.offset(filter_request.offset);
                 }
-            }
-        }
-
-        match filter_request.sorting() {
-            userservice::bpp_user_filters::SortingFields::HoursAsc => {
-                query = query.order_by(hours_seconds.asc());
-            }
-            userservice::bpp_user_filters::SortingFields::HoursDesc => {
-                query = query.order_by(hours_seconds.desc());
-            }
-            userservice::bpp_user_filters::SortingFields::MoneyAsc => {
-                query = query.order_by(money.asc());
-            }
-            userservice::bpp_user_filters::SortingFields::MoneyDesc => {
-                query = query.order_by(money.desc());
-            }
-            userservice::bpp_user_filters::SortingFields::Default => {}
-        }
-        let users = match query.load::<User>(&conn) {
-            Ok(users) => users,
-            Err(e) => {
-                error!("{}", e);
-                return Err(tonic::Status::internal("Failed to load users"));
-            }
-        };
-        let users: Vec<BppUser> = users
-            .into_iter()
-            .map(|user| user.to_userservice_user(&conn))
-            .collect();
-        let count = users.len() as i32;
 
-        return Ok(tonic::Response::new(userservice::BppUsers { users, count }));
+                // Pages are only stable if no two users can be in the same position
+                let paged = filter_request.limit > 0 || !filter_request.cursor.is_empty();
+                query = sort_users_query(query, &sort_keys);
+                if paged {
+                    query = query.then_order_by(schema::bpp_users::channel_id.asc());
+                }
+                failed_to = "load users";
+                let users = query.load::<User>(&conn)?;
+                // A full page may be followed by more users
+                let next_cursor = match users.last() {
+                    Some(last) if filter_request.limit > 0 && users.len() as i64 == filter_request.limit => {
+                        encode_cursor(last, &sort_keys)
+                    }
+                    _ => String::new(),
+                };
+                let users = User::to_userservice_users(&users, &conn)?;
+                Ok((users, count, next_cursor))
+            });
+            let (users, count, next_cursor) = match result {
+                Ok(result) => result,
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(tonic::Status::internal(format!("Failed to {}", failed_to)));
+                }
+            };
+            let count = count as i32;
+
+            Ok(tonic::Response::new(userservice::BppUsers {
+                users,
+                count,
+                next_cursor,
+            }))
+        })
+        .await
     }
 
     async fn update_user(
         &self,
         request: tonic::Request<userservice::BppUser>,
     ) -> Result<tonic::Response<userservice::BppUser>, tonic::Status> {
-        let user = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        let db_user: User = (&user).into();
-        db_user.save_to_database(&conn).unwrap();
-        return Ok(tonic::Response::new(user));
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("update_user", &request)?;
+            let actor = actor_of(&request);
+            let user = request.into_inner();
+            record_channel_id(&user.channel_id);
+            validate_user_update(&user)?;
+            let conn = server.conn()?;
+
+            let mut db_user = match User::get_active(&user.channel_id, &conn) {
+                Some(db_user) => db_user,
+                None => return Err(Status::not_found("User not found")),
+            };
+            let before = user_snapshot(&db_user);
+            db_user.apply_update(&user);
+
+            let mut concurrent_update = false;
+            let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+                let db_user = match db_user.save_if_version(user.version, &conn)? {
+                    Some(db_user) => db_user,
+                    None => {
+                        concurrent_update = true;
+                        return Err(diesel::result::Error::RollbackTransaction);
+                    }
+                };
+                AuditChange::new(&actor, "update_user")
+                    .target(&db_user.channel_id)
+                    .before(before)
+                    .after(user_snapshot(&db_user))
+                    .save(&conn)?;
+                Ok(db_user)
+            });
+            let db_user = match (result, concurrent_update) {
+                (Ok(db_user), _) => db_user,
+                (Err(_), true) => return Err(Status::aborted(CONCURRENT_UPDATE_MESSAGE)),
+                (Err(e), false) => {
+                    error!("{}", e);
+                    return Err(Status::internal("Failed to update user"));
+                }
+            };
+            Ok(tonic::Response::new(userservice_user(&db_user, &conn)?))
+        })
+        .await
     }
 
     async fn update_users(
         &self,
         request: tonic::Request<userservice::BppUsers>,
     ) -> Result<tonic::Response<userservice::BppUsers>, tonic::Status> {
-        let users = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        for user in &users.users {
-            let db_user: User = user.into();
-            db_user.save_to_database(&conn).unwrap();
-        }
-        return Ok(tonic::Response::new(users));
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("update_users", &request)?;
+            let actor = actor_of(&request);
+            let users = request.into_inner().users;
+            for user in &users {
+                validate_user_update(user)?;
+            }
+            let conn = server.conn()?;
+
+            // A missing or concurrently changed user rolls back the whole batch
+            let mut missing_user = None;
+            let mut changed_user = None;
+            let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+                let mut db_users = Vec::with_capacity(users.len());
+                for user in &users {
+                    let mut db_user = match User::get_active(&user.channel_id, &conn) {
+                        Some(db_user) => db_user,
+                        None => {
+                            missing_user = Some(user.channel_id.clone());
+                            return Err(diesel::result::Error::RollbackTransaction);
+                        }
+                    };
+                    let before = user_snapshot(&db_user);
+                    db_user.apply_update(user);
+                    match db_user.save_if_version(user.version, &conn)? {
+                        Some(db_user) => {
+                            AuditChange::new(&actor, "update_users")
+                                .target(&db_user.channel_id)
+                                .before(before)
+                                .after(user_snapshot(&db_user))
+                                .save(&conn)?;
+                            db_users.push(db_user);
+                        }
+                        None => {
+                            changed_user = Some(user.channel_id.clone());
+                            return Err(diesel::result::Error::RollbackTransaction);
+                        }
+                    }
+                }
+                Ok(db_users)
+            });
+
+            let db_users = match (result, missing_user, changed_user) {
+                (Ok(db_users), _, _) => db_users,
+                (Err(_), Some(missing_user), _) => {
+                    return Err(Status::not_found(format!("User {} not found", missing_user)))
+                }
+                (Err(_), None, Some(changed_user)) => {
+                    return Err(Status::aborted(format!("{} ({})", CONCURRENT_UPDATE_MESSAGE, changed_user)))
+                }
+                (Err(e), None, None) => {
+                    error!("{}", e);
+                    return Err(Status::internal("Failed to update users"));
+                }
+            };
+            let users = match User::to_userservice_users(&db_users, &conn) {
+                Ok(users) => users,
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(Status::internal("Failed to load the updated users"));
+                }
+            };
+            let count = users.len() as i32;
+            Ok(tonic::Response::new(userservice::BppUsers {
+                users,
+                count,
+                next_cursor: String::new(),
+            }))
+        })
+        .await
     }
 
     async fn delete_user(
         &self,
         request: tonic::Request<String>,
     ) -> Result<tonic::Response<()>, tonic::Status> {
-        let user_id = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        use schema::bpp_users::dsl::*;
-        diesel::delete(bpp_users.filter(channel_id.eq(user_id)))
-            .execute(&conn)
-            .unwrap();
-        return Ok(tonic::Response::new(()));
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("delete_user", &request)?;
+            let actor = actor_of(&request);
+            let user_id = request.into_inner();
+            record_channel_id(&user_id);
+            let conn = server.conn()?;
+            let user = match User::get_active(&user_id, &conn) {
+                Some(user) => user,
+                None => return Err(Status::not_found("User not found")),
+            };
+
+            // The row is kept for history, hard_delete_user erases it
+            let now = Utc::now().naive_utc();
+            let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+                let deleted = User::soft_delete(&[user_id], now, &conn)?;
+                if deleted > 0 {
+                    AuditChange::new(&actor, "delete_user")
+                        .target(&user.channel_id)
+                        .before(user_snapshot(&user))
+                        .save(&conn)?;
+                }
+                Ok(deleted)
+            });
+            match result {
+                Ok(0) => Err(Status::not_found("User not found")),
+                Ok(_) => Ok(tonic::Response::new(())),
+                Err(e) => {
+                    error!("{}", e);
+                    Err(Status::internal("Failed to delete user"))
+                }
+            }
+        })
+        .await
+    }
+
+    async fn hard_delete_user(
+        &self,
+        request: tonic::Request<String>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("hard_delete_user", &request)?;
+            let actor = actor_of(&request);
+            let user_id = request.into_inner();
+            record_channel_id(&user_id);
+            let conn = server.conn()?;
+            // Erases everything about the user, also if they were deleted before. The audit entry
+            // only names the user, a snapshot would keep the data that's supposed to be erased.
+            let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+                let erased = User::delete_from_database(&user_id, &conn)?;
+                if erased > 0 {
+                    AuditChange::new(&actor, "hard_delete_user").target(&user_id).save(&conn)?;
+                }
+                Ok(erased)
+            });
+            match result {
+                Ok(0) => Err(Status::not_found("User not found")),
+                Ok(_) => Ok(tonic::Response::new(())),
+                Err(e) => {
+                    error!("{}", e);
+                    Err(Status::internal("Failed to erase user"))
+                }
+            }
+        })
+        .await
     }
 
     async fn delete_users(
         &self,
         request: tonic::Request<userservice::BppUserIds>,
-    ) -> Result<tonic::Response<()>, tonic::Status> {
-        let user_ids = request.into_inner().users;
-        let conn = self.database_pool.get().unwrap();
-        use schema::bpp_users::dsl::*;
-        diesel::delete(bpp_users.filter(channel_id.eq_any(user_ids)))
-            .execute(&conn)
-            .unwrap();
-        return Ok(tonic::Response::new(()));
+    ) -> Result<tonic::Response<i32>, tonic::Status> {
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("delete_users", &request)?;
+            let actor = actor_of(&request);
+            let mut user_ids = request.into_inner().users;
+            user_ids.sort();
+            user_ids.dedup();
+            let conn = server.conn()?;
+
+            // A missing user rolls back the whole batch
+            let now = Utc::now().naive_utc();
+            let mut missing_user = None;
+            let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+                for user_id in &user_ids {
+                    let user = match User::find_active(user_id, &conn)? {
+                        Some(user) => user,
+                        None => {
+                            missing_user = Some(user_id.clone());
+                            return Err(diesel::result::Error::RollbackTransaction);
+                        }
+                    };
+                    User::soft_delete(std::slice::from_ref(user_id), now, &conn)?;
+                    AuditChange::new(&actor, "delete_users")
+                        .target(user_id)
+                        .before(user_snapshot(&user))
+                        .save(&conn)?;
+                }
+                Ok(user_ids.len() as i32)
+            });
+
+            match (result, missing_user) {
+                (Ok(count), _) => Ok(tonic::Response::new(count)),
+                (Err(_), Some(missing_user)) => {
+                    Err(Status::not_found(format!("User {} not found", missing_user)))
+                }
+                (Err(e), None) => {
+                    error!("{}", e);
+                    Err(Status::internal("Failed to delete users"))
+                }
+            }
+        })
+        .await
     }
 
     async fn create_user(
         &self,
         request: tonic::Request<userservice::BppUser>,
     ) -> Result<tonic::Response<userservice::BppUser>, tonic::Status> {
-        let user = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        let db_user: User = (&user).into();
-        db_user.save_to_database(&conn).unwrap();
-        return Ok(tonic::Response::new(user));
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("create_user", &request)?;
+            let actor = actor_of(&request);
+            let mut user = request.into_inner();
+            record_channel_id(&user.channel_id);
+            user.channel_id = match normalize_channel_id(&user.channel_id) {
+                Some(normalized) => normalized.to_string(),
+                None => {
+                    warn!("Rejecting user with malformed channel id {:?}", user.channel_id);
+                    return Err(Status::invalid_argument("The channel id is not a valid YouTube channel id"));
+                }
+            };
+            validate_user_update(&user)?;
+            let conn = server.conn()?;
+
+            let now = Utc::now().naive_utc();
+            let hours = user.hours.unwrap_or(prost_types::Duration {
+                seconds: 0,
+                nanos: 0,
+            });
+            let db_user = User::new(
+                user.channel_id,
+                user.display_name,
+                hours.seconds,
+                hours.nanos,
+                user.money,
+                now,
+                now,
+            );
+
+            use schema::bpp_users::dsl::*;
+            let result = conn.transaction(|| {
+                diesel::insert_into(bpp_users).values(&db_user).execute(&conn)?;
+                GroupUser::add_to_default_group(&server.default_group, &db_user.channel_id, &conn)?;
+                AuditChange::new(&actor, "create_user")
+                    .target(&db_user.channel_id)
+                    .after(user_snapshot(&db_user))
+                    .save(&conn)?;
+                Ok(())
+            });
+            match result {
+                Ok(()) => {}
+                Err(diesel::result::Error::DatabaseError(
+                    diesel::result::DatabaseErrorKind::UniqueViolation,
+                    _,
+                )) => return Err(Status::already_exists("User already exists")),
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(Status::internal("Failed to create user"));
+                }
+            }
+            Ok(tonic::Response::new(userservice_user(&db_user, &conn)?))
+        })
+        .await
     }
 
     async fn user_has_permission(
         &self,
         request: tonic::Request<userservice::UserPermissionCheck>,
     ) -> Result<tonic::Response<bool>, tonic::Status> {
-        let check = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-
-        let user_id = check.channel_id;
-        let permission = check.permission;
-        let mut has_permission = check.granted_default;
-
-        let mut user_groups = Group::get_groups_for_user(user_id.clone(), &conn);
-        user_groups.sort();
-        for group in user_groups {
-            let group_permissions =
-                GroupPermission::get_permissions_for_group(group.group_id, &conn);
-            let searched_permission = group_permissions
-                .iter()
-                .find(|group_permission| group_permission.permission == permission);
-            if let Some(searched_permission) = searched_permission {
-                has_permission = searched_permission.granted;
+        self.limit_rate(&request)?;
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            let check = request.into_inner();
+            record_channel_id(&check.channel_id);
+            let conn = server.conn()?;
+
+            // Unknown users don't have any permissions, not even the default ones
+            if !user_exists(&check.channel_id, &conn)? {
+                return Ok(tonic::Response::new(false));
             }
-        }
 
-        let user_permissions = UserPermission::get_permissions_for_user(user_id, &conn);
-        let searched_permission = user_permissions
-            .iter()
-            .find(|perm| perm.permission == permission);
-        if let Some(searched_permission) = searched_permission {
-            has_permission = searched_permission.granted;
+            let has_permission = match resolve_user_permission(
+                &check.channel_id,
+                &check.permission,
+                check.granted_default,
+                &conn,
+            ) {
+                Ok(has_permission) => has_permission,
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(Status::internal("Failed to resolve the permission"));
+                }
+            };
+
+            Ok(tonic::Response::new(has_permission))
+        })
+        .await
+    }
+
+    async fn list_user_permissions(
+        &self,
+        request: tonic::Request<String>,
+    ) -> Result<tonic::Response<userservice::EffectivePermissions>, tonic::Status> {
+        self.limit_rate(&request)?;
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            let channel_id = request.into_inner();
+            record_channel_id(&channel_id);
+            let conn = server.conn()?;
+            if !user_exists(&channel_id, &conn)? {
+                return Err(tonic::Status::not_found("User not found"));
+            }
+
+            let permissions = match list_user_permissions(&channel_id, &conn) {
+                Ok(permissions) => permissions,
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(Status::internal("Failed to resolve the permissions"));
+                }
+            };
+            let permissions = permissions
+                .into_iter()
+                .map(|resolved| {
+                    let (direct, group_id, group_name) = match resolved.source {
+                        PermissionSource::Direct => (true, 0, String::new()),
+                        PermissionSource::Group { group_id, group_name } => (false, group_id, group_name),
+                    };
+                    userservice::EffectivePermission {
+                        permission: resolved.permission,
+                        granted: resolved.granted,
+                        direct,
+                        group_id,
+                        group_name,
+                    }
+                })
+                .collect();
+
+            Ok(tonic::Response::new(userservice::EffectivePermissions { permissions }))
+        })
+        .await
+    }
+
+    async fn get_stats(
+        &self,
+        request: tonic::Request<userservice::BppUserStatsRequest>,
+    ) -> Result<tonic::Response<userservice::BppUserStats>, tonic::Status> {
+        self.limit_rate(&request)?;
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            let stats_request = request.into_inner();
+            let recent_window = match stats_request.recent_window {
+                Some(window) => chrono::Duration::seconds(window.seconds),
+                None => chrono::Duration::hours(24),
+            };
+            if recent_window < chrono::Duration::zero() {
+                return Err(tonic::Status::invalid_argument("The recent window must not be negative"));
+            }
+            let conn = server.conn()?;
+
+            let recent_since = Utc::now().naive_utc() - recent_window;
+            let stats = match User::get_stats(recent_since, &conn) {
+                Ok(stats) => stats,
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(tonic::Status::internal("Failed to get user statistics"));
+                }
+            };
+
+            Ok(tonic::Response::new(userservice::BppUserStats {
+                total_users: stats.total_users,
+                recent_users: stats.recent_users,
+                total_money: stats.total_money,
+                total_hours: Some(prost_types::Duration {
+                    seconds: stats.total_hours_seconds,
+                    nanos: 0,
+                }),
+            }))
+        })
+        .await
+    }
+
+    type ExportUsersStream =
+        Pin<Box<dyn Stream<Item = Result<BppUser, Status>> + Send + Sync + 'static>>;
+
+    async fn economy_summary(
+        &self,
+        request: tonic::Request<()>,
+    ) -> Result<tonic::Response<userservice::EconomySummaryResult>, tonic::Status> {
+        self.limit_rate(&request)?;
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            let conn = server.conn()?;
+            let summary = User::get_economy_summary(&conn).and_then(|summary| {
+                let top_holder = match &summary.top_holder {
+                    Some(top_holder) => Some(top_holder.try_to_userservice_user(&conn)?),
+                    None => None,
+                };
+                Ok((summary, top_holder))
+            });
+            let (summary, top_holder) = match summary {
+                Ok(summary) => summary,
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(tonic::Status::internal("Failed to summarize the economy"));
+                }
+            };
+
+            Ok(tonic::Response::new(userservice::EconomySummaryResult {
+                total_money: summary.total_money,
+                average_money: summary.average_money,
+                top_holder,
+            }))
+        })
+        .await
+    }
+
+    async fn export_users(
+        &self,
+        request: tonic::Request<userservice::ExportUsersRequest>,
+    ) -> Result<tonic::Response<Self::ExportUsersStream>, tonic::Status> {
+        self.limit_rate(&request)?;
+        let server = self.clone();
+        let export_request = run_blocking(self.database_timeout, move || {
+            server.authorize("export_users", &request)?;
+            Ok(request.into_inner())
+        })
+        .await?;
+        let batch_size = match export_request.batch_size {
+            size if size < 0 => return Err(Status::invalid_argument("The batch size must not be negative")),
+            0 => DEFAULT_EXPORT_BATCH_SIZE,
+            size => std::cmp::min(size, MAX_EXPORT_BATCH_SIZE),
+        };
+        let after_channel_id = match (export_request.after_channel_id, export_request.cursor) {
+            (after_channel_id, cursor) if cursor.is_empty() => after_channel_id,
+            (after_channel_id, cursor) if after_channel_id.is_empty() => match decode_channel_id_cursor(&cursor) {
+                Some(after_channel_id) => after_channel_id,
+                None => return Err(Status::invalid_argument("Invalid cursor for an export")),
+            },
+            _ => {
+                return Err(Status::invalid_argument(
+                    "A cursor can't be combined with an after_channel_id",
+                ))
+            }
+        };
+        let pool = self.database_pool.clone();
+        let database_timeout = self.database_timeout;
+        let shutdown = self.shutdown.clone();
+
+        // Only one batch is kept in memory, and the connection is given back between batches
+        let stream = async_stream::stream! {
+            let mut after_channel_id = after_channel_id;
+            loop {
+                if *shutdown.borrow() {
+                    let status = Status::unavailable("Shutting down, resume the export later");
+                    yield Err(with_resume_cursor(status, &after_channel_id));
+                    break;
+                }
+                let batch_pool = pool.clone();
+                let batch_after = after_channel_id.clone();
+                let batch = run_blocking(database_timeout, move || {
+                    load_export_batch(&batch_pool, &batch_after, batch_size)
+                });
+                let batch = match batch.await {
+                    Ok(batch) => batch,
+                    Err(status) => {
+                        yield Err(with_resume_cursor(status, &after_channel_id));
+                        break;
+                    }
+                };
+
+                let done = (batch.len() as i64) < batch_size;
+                if let Some(last) = batch.last() {
+                    after_channel_id = last.channel_id.clone();
+                }
+                for user in batch {
+                    yield Ok(user);
+                }
+                if done {
+                    break;
+                }
+            }
+        };
+
+        Ok(tonic::Response::new(Box::pin(stream)))
+    }
+
+    async fn import_users(
+        &self,
+        request: tonic::Request<Streaming<BppUser>>,
+    ) -> Result<tonic::Response<userservice::ImportSummary>, tonic::Status> {
+        // Only the metadata is needed for authorizing, the stream stays here
+        let mut authorize_request = Request::new(());
+        *authorize_request.metadata_mut() = request.metadata().clone();
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || server.authorize("import_users", &authorize_request)).await?;
+        let actor = actor_of(&request);
+        let mut stream = request.into_inner();
+
+        let mut summary = userservice::ImportSummary::default();
+        // Users are keyed by channel id, so a user appearing twice in a batch is only written once
+        let mut batch: HashMap<String, User> = HashMap::with_capacity(IMPORT_BATCH_SIZE);
+        let mut index = -1;
+        loop {
+            let user = stream.message().await?;
+            if let Some(user) = &user {
+                index += 1;
+                match import_row(user) {
+                    Ok(db_user) => {
+                        batch.insert(db_user.channel_id.clone(), db_user);
+                    }
+                    Err(reason) => summary.errors.push(userservice::ImportError {
+                        index,
+                        channel_id: user.channel_id.clone(),
+                        reason,
+                    }),
+                }
+            }
+
+            // Every batch is its own transaction, so a long import doesn't hold one open
+            if batch.len() >= IMPORT_BATCH_SIZE || (user.is_none() && !batch.is_empty()) {
+                let users: Vec<User> = batch.drain().map(|(_, user)| user).collect();
+                let server = self.clone();
+                let actor = actor.clone();
+                let imported = run_blocking(self.database_timeout, move || {
+                    let conn = server.conn()?;
+                    Ok(conn.transaction::<_, diesel::result::Error, _>(|| {
+                        let (inserted_ids, updated) = User::import_many(&users, &conn)?;
+                        for inserted_id in &inserted_ids {
+                            GroupUser::add_to_default_group(&server.default_group, inserted_id, &conn)?;
+                        }
+                        let inserted = inserted_ids.len();
+                        // An import can touch every user, so only the numbers are recorded
+                        AuditChange::new(&actor, "import_users")
+                            .affected((inserted + updated) as i64)
+                            .after(serde_json::json!({ "inserted": inserted, "updated": updated }))
+                            .save(&conn)?;
+                        Ok((inserted, updated))
+                    }))
+                });
+                match imported.await? {
+                    Ok((inserted, updated)) => {
+                        summary.inserted += inserted as i64;
+                        summary.updated += updated as i64;
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        return Err(Status::internal(format!(
+                            "Failed to import users, {} were imported before the failure",
+                            summary.inserted + summary.updated
+                        )));
+                    }
+                }
+            }
+            if user.is_none() {
+                break;
+            }
         }
 
-        return Ok(tonic::Response::new(has_permission));
+        info!(
+            "Imported users: {} inserted, {} updated, {} invalid",
+            summary.inserted,
+            summary.updated,
+            summary.errors.len()
+        );
+        Ok(tonic::Response::new(summary))
+    }
+
+    async fn merge_users(
+        &self,
+        request: tonic::Request<userservice::MergeUsersRequest>,
+    ) -> Result<tonic::Response<userservice::BppUser>, tonic::Status> {
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("merge_users", &request)?;
+            let actor = actor_of(&request);
+            let merge = request.into_inner();
+            record_channel_id(&merge.target_channel_id);
+            if merge.source_channel_id == merge.target_channel_id {
+                return Err(Status::invalid_argument("A user can't be merged into themselves"));
+            }
+            let conn = server.conn()?;
+
+            let mut rejection = None;
+            let now = Utc::now().naive_utc();
+            let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+                let users = User::get_many_for_update(&[&merge.source_channel_id, &merge.target_channel_id], &conn)?;
+                let source = users.iter().find(|user| user.channel_id == merge.source_channel_id);
+                let target = users.iter().find(|user| user.channel_id == merge.target_channel_id);
+                let (source, target) = match (source, target) {
+                    (Some(source), Some(target)) => (source, target),
+                    (None, _) => {
+                        rejection = Some(Status::not_found("Source user not found"));
+                        return Err(diesel::result::Error::RollbackTransaction);
+                    }
+                    (_, None) => {
+                        rejection = Some(Status::not_found("Target user not found"));
+                        return Err(diesel::result::Error::RollbackTransaction);
+                    }
+                };
+
+                let merged = User::merge(source, target, now, &conn)?;
+                AuditChange::new(&actor, "merge_users")
+                    .target(&merged.channel_id)
+                    .before(serde_json::json!({
+                        "source": user_snapshot(source),
+                        "target": user_snapshot(target),
+                    }))
+                    .after(user_snapshot(&merged))
+                    .save(&conn)?;
+                Ok(merged)
+            });
+
+            let merged = match (result, rejection) {
+                (Ok(merged), _) => merged,
+                (Err(_), Some(rejection)) => return Err(rejection),
+                (Err(e), None) => {
+                    error!("{}", e);
+                    return Err(Status::internal("Failed to merge users"));
+                }
+            };
+            info!("Merged user {} into {}", merge.source_channel_id, merge.target_channel_id);
+            Ok(tonic::Response::new(userservice_user(&merged, &conn)?))
+        })
+        .await
+    }
+
+    async fn get_active_users(
+        &self,
+        request: tonic::Request<userservice::ActiveUsersRequest>,
+    ) -> Result<tonic::Response<userservice::BppUsers>, tonic::Status> {
+        self.limit_rate(&request)?;
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            let active_request = request.into_inner();
+            if active_request.limit < 0 {
+                return Err(Status::invalid_argument("The limit must not be negative"));
+            }
+            let window = match active_request.window {
+                Some(window) => match std::time::Duration::try_from(window)
+                    .ok()
+                    .and_then(|window| chrono::Duration::from_std(window).ok())
+                {
+                    Some(window) => window,
+                    None => return Err(Status::invalid_argument("The window must not be negative")),
+                },
+                None => server.active_window,
+            };
+            let since = match Utc::now().naive_utc().checked_sub_signed(window) {
+                Some(since) => since,
+                None => return Err(Status::invalid_argument("The window is too long")),
+            };
+            let conn = server.conn()?;
+
+            let users = User::get_active_since(since, active_request.limit, &conn)
+                .and_then(|users| User::to_userservice_users(&users, &conn));
+            match users {
+                Ok(users) => {
+                    let count = users.len() as i32;
+                    Ok(tonic::Response::new(userservice::BppUsers {
+                        users,
+                        count,
+                        next_cursor: String::new(),
+                    }))
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    Err(Status::internal("Failed to load active users"))
+                }
+            }
+        })
+        .await
+    }
+
+    async fn get_leaderboard(
+        &self,
+        request: tonic::Request<userservice::LeaderboardRequest>,
+    ) -> Result<tonic::Response<userservice::Leaderboard>, tonic::Status> {
+        self.limit_rate(&request)?;
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            use schema::bpp_users::dsl::*;
+            use userservice::leaderboard_request::Metric;
+
+            let leaderboard_request = request.into_inner();
+            let limit = match leaderboard_request.limit {
+                l if l < 0 => return Err(Status::invalid_argument("The limit must not be negative")),
+                0 => DEFAULT_LEADERBOARD_LIMIT,
+                l => std::cmp::min(l, MAX_LEADERBOARD_LIMIT),
+            };
+            let conn = server.conn()?;
+
+            let query = bpp_users.filter(deleted_at.is_null()).into_boxed();
+            let query = match leaderboard_request.metric() {
+                // The nanos are part of the hours, otherwise users within the same second would tie
+                Metric::Hours => query.order((hours_seconds.desc(), hours_nanos.desc())),
+                Metric::Money => query.order(money.desc()),
+            };
+            // Equal values are ordered by who was seen first, the channel id only settles exact ties
+            let leaders = query
+                .then_order_by(first_seen_at.asc())
+                .then_order_by(channel_id.asc())
+                .limit(limit)
+                .load::<User>(&conn);
+            let leaders = match leaders {
+                Ok(leaders) => leaders,
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(Status::internal("Failed to get leaderboard"));
+                }
+            };
+
+            let entries = leaders
+                .into_iter()
+                .enumerate()
+                .map(|(index, user)| userservice::LeaderboardEntry {
+                    position: index as i64 + 1,
+                    channel_id: user.channel_id,
+                    display_name: user.display_name,
+                    hours: Some(prost_types::Duration {
+                        seconds: user.hours_seconds,
+                        nanos: user.hours_nanos,
+                    }),
+                    money: user.money,
+                })
+                .collect();
+
+            Ok(tonic::Response::new(userservice::Leaderboard { entries }))
+        })
+        .await
+    }
+
+    async fn adjust_money(
+        &self,
+        request: tonic::Request<userservice::MoneyAdjustment>,
+    ) -> Result<tonic::Response<userservice::BppUser>, tonic::Status> {
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("adjust_money", &request)?;
+            let actor = actor_of(&request);
+            let adjustment = request.into_inner();
+            record_channel_id(&adjustment.channel_id);
+            if !adjustment.delta.is_finite() {
+                return Err(tonic::Status::invalid_argument("The money delta must be a finite number"));
+            }
+            let conn = server.conn()?;
+
+            let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+                let user = User::adjust_money(&adjustment.channel_id, adjustment.delta, &conn)?;
+                if let Some(user) = &user {
+                    AuditChange::new(&actor, "adjust_money")
+                        .target(&user.channel_id)
+                        .before(serde_json::json!({ "money": user.money - adjustment.delta }))
+                        .after(serde_json::json!({ "money": user.money, "delta": adjustment.delta }))
+                        .save(&conn)?;
+                }
+                Ok(user)
+            });
+            match result {
+                Ok(Some(user)) => {
+                    return Ok(tonic::Response::new(userservice_user(&user, &conn)?));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(tonic::Status::internal("Failed to adjust money"));
+                }
+            }
+
+            // Nothing was updated, either because the user is missing or because they can't afford it
+            if !user_exists(&adjustment.channel_id, &conn)? {
+                return Err(tonic::Status::not_found("User not found"));
+            }
+            Err(tonic::Status::failed_precondition("The user does not have enough money"))
+        })
+        .await
+    }
+
+    async fn reset_all_money(
+        &self,
+        request: tonic::Request<userservice::ResetRequest>,
+    ) -> Result<tonic::Response<userservice::ResetResult>, tonic::Status> {
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("reset_all_money", &request)?;
+            let confirmation = &request.get_ref().confirmation;
+            let result = server.reset_all("reset_all_money", confirmation, &request, User::reset_all_money)?;
+            Ok(tonic::Response::new(result))
+        })
+        .await
+    }
+
+    async fn reset_all_hours(
+        &self,
+        request: tonic::Request<userservice::ResetRequest>,
+    ) -> Result<tonic::Response<userservice::ResetResult>, tonic::Status> {
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("reset_all_hours", &request)?;
+            let confirmation = &request.get_ref().confirmation;
+            let result = server.reset_all("reset_all_hours", confirmation, &request, User::reset_all_hours)?;
+            Ok(tonic::Response::new(result))
+        })
+        .await
+    }
+
+    async fn list_known_permissions(
+        &self,
+        request: tonic::Request<()>,
+    ) -> Result<tonic::Response<userservice::KnownPermissions>, tonic::Status> {
+        self.limit_rate(&request)?;
+        let permissions = self.permission_registry.permissions().map(str::to_string).collect();
+        Ok(tonic::Response::new(userservice::KnownPermissions { permissions }))
+    }
+
+    async fn describe_schema(
+        &self,
+        request: tonic::Request<()>,
+    ) -> Result<tonic::Response<userservice::SchemaDescription>, tonic::Status> {
+        self.limit_rate(&request)?;
+        Ok(tonic::Response::new(userservice::SchemaDescription {
+            filters: FILTER_NAMES.iter().map(|name| name.to_string()).collect(),
+            sort_fields: sort_field_names().into_iter().map(str::to_string).collect(),
+            permissions: self.permission_registry.permissions().map(str::to_string).collect(),
+        }))
+    }
+
+    async fn get_audit_log(
+        &self,
+        request: tonic::Request<userservice::AuditLogRequest>,
+    ) -> Result<tonic::Response<userservice::AuditLog>, tonic::Status> {
+        self.limit_rate(&request)?;
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("get_audit_log", &request)?;
+            let audit_request = request.into_inner();
+            let limit = match audit_request.limit {
+                0 => DEFAULT_AUDIT_LOG_LIMIT,
+                limit if limit < 0 => return Err(Status::invalid_argument("The limit can't be negative")),
+                limit => limit.min(MAX_AUDIT_LOG_LIMIT),
+            };
+            let from = audit_timestamp(audit_request.from)?;
+            let to = audit_timestamp(audit_request.to)?;
+            let target_id = Some(audit_request.target_id.as_str()).filter(|target_id| !target_id.is_empty());
+
+            let conn = server.conn()?;
+            let entries = match AuditEntry::find(target_id, from, to, limit, &conn) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(Status::internal("Failed to load the audit log"));
+                }
+            };
+            Ok(tonic::Response::new(userservice::AuditLog {
+                entries: entries.iter().map(AuditEntry::to_userservice_entry).collect(),
+            }))
+        })
+        .await
+    }
+
+    async fn transfer_money(
+        &self,
+        request: tonic::Request<userservice::MoneyTransfer>,
+    ) -> Result<tonic::Response<userservice::MoneyTransferResult>, tonic::Status> {
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("transfer_money", &request)?;
+            let actor = actor_of(&request);
+            let transfer = request.into_inner();
+            record_channel_id(&transfer.sender_channel_id);
+            if transfer.sender_channel_id == transfer.recipient_channel_id {
+                return Err(Status::invalid_argument("Users can't transfer money to themselves"));
+            }
+            if !transfer.amount.is_finite() || transfer.amount <= 0.0 {
+                return Err(Status::invalid_argument("The amount must be a positive number"));
+            }
+            let conn = server.conn()?;
+
+            // Both users are locked first, so the balance can't change between the check and the update
+            let mut rejection = None;
+            let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+                let users = User::get_many_for_update(
+                    &[&transfer.sender_channel_id, &transfer.recipient_channel_id],
+                    &conn,
+                )?;
+                let sender = users.iter().find(|user| user.channel_id == transfer.sender_channel_id);
+                let recipient = users.iter().find(|user| user.channel_id == transfer.recipient_channel_id);
+                match (sender, recipient) {
+                    (None, _) => rejection = Some(Status::not_found("Sender not found")),
+                    (_, None) => rejection = Some(Status::not_found("Recipient not found")),
+                    (Some(sender), _) if sender.money < transfer.amount => {
+                        rejection = Some(Status::failed_precondition("The sender does not have enough money"))
+                    }
+                    _ => {}
+                }
+                if rejection.is_some() {
+                    return Err(diesel::result::Error::RollbackTransaction);
+                }
+
+                let before = serde_json::json!({
+                    "sender_money": sender.map(|sender| sender.money),
+                    "recipient_money": recipient.map(|recipient| recipient.money),
+                });
+                let sender = User::adjust_money(&transfer.sender_channel_id, -transfer.amount, &conn)?;
+                let recipient = User::adjust_money(&transfer.recipient_channel_id, transfer.amount, &conn)?;
+                match (sender, recipient) {
+                    (Some(sender), Some(recipient)) => {
+                        AuditChange::new(&actor, "transfer_money")
+                            .target(&sender.channel_id)
+                            .before(before)
+                            .after(serde_json::json!({
+                                "recipient": recipient.channel_id,
+                                "amount": transfer.amount,
+                                "sender_money": sender.money,
+                                "recipient_money": recipient.money,
+                            }))
+                            .save(&conn)?;
+                        Ok((sender, recipient))
+                    }
+                    _ => Err(diesel::result::Error::RollbackTransaction),
+                }
+            });
+
+            let (sender, recipient) = match (result, rejection) {
+                (Ok(users), _) => users,
+                (Err(_), Some(rejection)) => return Err(rejection),
+                (Err(e), None) => {
+                    error!("{}", e);
+                    return Err(Status::internal("Failed to transfer money"));
+                }
+            };
+
+            Ok(tonic::Response::new(userservice::MoneyTransferResult {
+                sender: Some(userservice_user(&sender, &conn)?),
+                recipient: Some(userservice_user(&recipient, &conn)?),
+            }))
+        })
+        .await
     }
 
     async fn get_group(&self, request: Request<i32>) -> Result<Response<userservice::BppGroup>, Status> {
-        let group_id = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        let group = Group::get_from_database(&group_id, &conn);
-        if group.is_none() {
-            return Err(Status::not_found("Group not found"));
-        }
-        let group = group.unwrap();
-        let group_permissions = GroupPermission::get_permissions_for_group(group_id, &conn);
-        let group_permissions: Vec<userservice::Permission> = group_permissions
-            .into_iter()
-            .map(|group_permission| userservice::Permission {
-                permission: group_permission.permission,
-                granted: group_permission.granted,
-            })
-            .collect();
-        let bpp_group = BppGroup {
-            group_id: group.group_id,
-            group_name: group.group_name,
-            permissions: group_permissions,
-            bonus_payout: group.bonus_payout,
-            group_sorting: group.group_sorting,
-        };
-        return Ok(Response::new(bpp_group));
+        self.limit_rate(&request)?;
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            let group_id = request.into_inner();
+            let conn = server.conn()?;
+            let group = match Group::get_from_database(&group_id, &conn) {
+                Some(group) => group,
+                None => return Err(Status::not_found("Group not found")),
+            };
+                        let bpp_group = userservice_group(&group, &conn)?;
+            Ok(Response::new(bpp_group))
+        })
+        .await
     }
 
     async fn get_groups(
         &self,
-        _: tonic::Request<()>,
+        request: tonic::Request<()>,
     ) -> Result<tonic::Response<userservice::BppGroups>, tonic::Status> {
-        let conn = self.database_pool.get().unwrap();
-        use schema::bpp_groups::dsl::*;
-        let groups = bpp_groups
-            .order(group_sorting.desc())
-            .load::<Group>(&conn)
-            .unwrap();
-        let groups: Vec<BppGroup> = groups
-            .into_iter()
-            .map(|group| {
-                let group_permissions =
-                    GroupPermission::get_permissions_for_group(group.group_id, &conn);
-                let group_permissions: Vec<userservice::Permission> = group_permissions
-                    .into_iter()
-                    .map(|p| userservice::Permission {
-                        permission: p.permission,
-                        granted: p.granted,
-                    })
-                    .collect();
-
-                BppGroup {
-                    group_id: group.group_id,
-                    group_name: group.group_name,
-                    permissions: group_permissions,
-                    bonus_payout: group.bonus_payout,
-                    group_sorting: group.group_sorting,
+        self.limit_rate(&request)?;
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            let conn = server.conn()?;
+            use schema::bpp_groups::dsl::*;
+            let groups = match bpp_groups.order(group_name.asc()).load::<Group>(&conn) {
+                Ok(groups) => groups,
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(Status::internal("Failed to load groups"));
                 }
-            })
-            .collect();
-        let count = groups.len() as i32;
-        return Ok(tonic::Response::new(userservice::BppGroups {
-            groups,
-            count,
-        }));
+            };
+            let groups = groups
+                .iter()
+                .map(|group| userservice_group(group, &conn))
+                .collect::<Result<Vec<BppGroup>, Status>>()?;
+            let count = groups.len() as i32;
+            Ok(tonic::Response::new(userservice::BppGroups {
+                groups,
+                count,
+            }))
+        })
+        .await
     }
 
     async fn update_group(
         &self,
         request: tonic::Request<userservice::BppGroup>,
     ) -> Result<tonic::Response<userservice::BppGroup>, tonic::Status> {
-        let group = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        let db_group: Group = (&group).into();
-        db_group.save_to_database(&conn).unwrap();
-        return Ok(tonic::Response::new(group));
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("update_group", &request)?;
+            let actor = actor_of(&request);
+            let group = request.into_inner();
+            validate_group_name(&group.group_name)?;
+            server.validate_permissions(group.permissions.iter().map(|p| p.permission.as_str()))?;
+            let conn = server.conn()?;
+
+            let result = conn.transaction(|| save_group_with_permissions(&actor, "update_group", &group, &conn));
+            let db_group = match result {
+                Ok(Some(db_group)) => db_group,
+                Ok(None) => return Err(Status::not_found("Group not found")),
+                Err(e) if is_unique_violation(&e) => return Err(Status::already_exists("Group already exists")),
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(Status::internal("Failed to update group"));
+                }
+            };
+
+            Ok(tonic::Response::new(userservice_group(&db_group, &conn)?))
+        })
+        .await
     }
 
     async fn update_groups(
         &self,
         request: tonic::Request<userservice::BppGroups>,
     ) -> Result<tonic::Response<userservice::BppGroups>, tonic::Status> {
-        let groups = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        for group in &groups.groups {
-            let db_group: Group = group.into();
-            db_group.save_to_database(&conn).unwrap();
-        }
-        return Ok(tonic::Response::new(groups));
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("update_groups", &request)?;
+            let actor = actor_of(&request);
+            let groups = request.into_inner().groups;
+            for group in &groups {
+                validate_group_name(&group.group_name)?;
+                server.validate_permissions(group.permissions.iter().map(|p| p.permission.as_str()))?;
+            }
+            let conn = server.conn()?;
+
+            // An unknown group rolls back the whole batch
+            let mut missing_group = None;
+            let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+                let mut updated_groups = Vec::with_capacity(groups.len());
+                for group in &groups {
+                    match save_group_with_permissions(&actor, "update_groups", group, &conn)? {
+                        Some(db_group) => updated_groups.push(db_group),
+                        None => {
+                            missing_group = Some(group.group_id);
+                            return Err(diesel::result::Error::RollbackTransaction);
+                        }
+                    }
+                }
+                Ok(updated_groups)
+            });
+            let updated_groups = match (result, missing_group) {
+                (Ok(updated_groups), _) => updated_groups,
+                (Err(_), Some(missing_group)) => {
+                    return Err(Status::not_found(format!("Group {} not found", missing_group)))
+                }
+                (Err(e), None) if is_unique_violation(&e) => {
+                    return Err(Status::already_exists("Group already exists"))
+                }
+                (Err(e), None) => {
+                    error!("{}", e);
+                    return Err(Status::internal("Failed to update groups"));
+                }
+            };
+
+            let groups = updated_groups
+                .iter()
+                .map(|group| userservice_group(group, &conn))
+                .collect::<Result<Vec<BppGroup>, Status>>()?;
+            let count = groups.len() as i32;
+            Ok(tonic::Response::new(userservice::BppGroups { groups, count }))
+        })
+        .await
+    }
+
+    async fn rename_group(
+        &self,
+        request: tonic::Request<userservice::GroupRename>,
+    ) -> Result<tonic::Response<userservice::BppGroup>, tonic::Status> {
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("rename_group", &request)?;
+            let actor = actor_of(&request);
+            let rename = request.into_inner();
+            validate_group_name(&rename.group_name)?;
+            let conn = server.conn()?;
+
+            let mut rejection = None;
+            let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+                let before = match Group::get_from_database(&rename.group_id, &conn) {
+                    Some(group) => group,
+                    None => {
+                        rejection = Some(Status::not_found("Group not found"));
+                        return Err(diesel::result::Error::RollbackTransaction);
+                    }
+                };
+                let renamed = Group::rename(rename.group_id, &rename.group_name, &conn)?
+                    .ok_or(diesel::result::Error::NotFound)?;
+                AuditChange::new(&actor, "rename_group")
+                    .target(renamed.group_id)
+                    .before(group_snapshot(&before))
+                    .after(group_snapshot(&renamed))
+                    .save(&conn)?;
+                Ok(renamed)
+            });
+            let renamed = match (result, rejection) {
+                (Ok(renamed), _) => renamed,
+                (Err(_), Some(rejection)) => return Err(rejection),
+                (Err(e), None) if is_unique_violation(&e) => {
+                    return Err(Status::already_exists("Group already exists"))
+                }
+                (Err(e), None) => {
+                    error!("{}", e);
+                    return Err(Status::internal("Failed to rename group"));
+                }
+            };
+
+            Ok(tonic::Response::new(userservice_group(&renamed, &conn)?))
+        })
+        .await
     }
 
     async fn delete_group(
         &self,
         request: tonic::Request<i32>,
     ) -> Result<tonic::Response<()>, tonic::Status> {
-        let id = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        use schema::bpp_groups::dsl::*;
-        diesel::delete(bpp_groups.filter(group_id.eq(id)))
-            .execute(&conn)
-            .unwrap();
-        return Ok(tonic::Response::new(()));
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("delete_group", &request)?;
+            let actor = actor_of(&request);
+            let id = request.into_inner();
+            let conn = server.conn()?;
+            let group = match Group::get_from_database(&id, &conn) {
+                Some(group) => group,
+                None => return Err(Status::not_found("Group not found")),
+            };
+            // Deleting a group with members would silently strip their permissions
+            let member_count = group.try_get_member_count(&conn).map_err(|e| {
+                error!("{}", e);
+                Status::internal("Failed to count the members of group")
+            })?;
+            if member_count > 0 {
+                return Err(Status::failed_precondition("Group still has members"));
+            }
+
+            let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+                Group::delete_from_database(id, &conn)?;
+                AuditChange::new(&actor, "delete_group")
+                    .target(id)
+                    .before(group_snapshot(&group))
+                    .save(&conn)?;
+                Ok(())
+            });
+            if let Err(e) = result {
+                error!("{}", e);
+                return Err(Status::internal("Failed to delete group"));
+            }
+            Ok(tonic::Response::new(()))
+        })
+        .await
     }
 
     async fn delete_groups(
         &self,
         request: tonic::Request<userservice::BppGroupIds>,
     ) -> Result<tonic::Response<()>, tonic::Status> {
-        let group_ids = request.into_inner().groups;
-        let conn = self.database_pool.get().unwrap();
-        use schema::bpp_groups::dsl::*;
-        diesel::delete(bpp_groups.filter(group_id.eq_any(group_ids)))
-            .execute(&conn)
-            .unwrap();
-        return Ok(tonic::Response::new(()));
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("delete_groups", &request)?;
+            let actor = actor_of(&request);
+            let group_ids = request.into_inner().groups;
+            let conn = server.conn()?;
+            for id in &group_ids {
+                if let Some(group) = Group::get_from_database(id, &conn) {
+                    let member_count = group.try_get_member_count(&conn).map_err(|e| {
+                        error!("{}", e);
+                        Status::internal("Failed to count the members of group")
+                    })?;
+                    if member_count > 0 {
+                        return Err(Status::failed_precondition(format!(
+                            "Group {} still has members",
+                            id
+                        )));
+                    }
+                }
+            }
+
+            let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+                for id in group_ids {
+                    let group = Group::get_from_database(&id, &conn);
+                    Group::delete_from_database(id, &conn)?;
+                    if let Some(group) = group {
+                        AuditChange::new(&actor, "delete_groups")
+                            .target(id)
+                            .before(group_snapshot(&group))
+                            .save(&conn)?;
+                    }
+                }
+                Ok(())
+            });
+            if let Err(e) = result {
+                error!("{}", e);
+                return Err(Status::internal("Failed to delete groups"));
+            }
+            Ok(tonic::Response::new(()))
+        })
+        .await
     }
 
     async fn create_group(
         &self,
         request: tonic::Request<userservice::CreateBppGroup>,
     ) -> Result<tonic::Response<userservice::BppGroup>, tonic::Status> {
-        let create_group = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        let db_group: InsertGroup = create_group.into();
-        let created_group = db_group.save_to_database(&conn).unwrap();
-
-        let group_permissions =
-            GroupPermission::get_permissions_for_group(created_group.group_id, &conn);
-        let group_permissions: Vec<userservice::Permission> = group_permissions
-            .into_iter()
-            .map(|p| userservice::Permission {
-                permission: p.permission,
-                granted: p.granted,
-            })
-            .collect();
-
-        let group = BppGroup {
-            group_id: created_group.group_id,
-            group_name: created_group.group_name,
-            permissions: group_permissions,
-            bonus_payout: created_group.bonus_payout,
-            group_sorting: created_group.group_sorting,
-        };
-        return Ok(tonic::Response::new(group));
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("create_group", &request)?;
+            let actor = actor_of(&request);
+            let mut create_group = request.into_inner();
+            validate_group_name(&create_group.group_name)?;
+            server.validate_permissions(create_group.permissions.iter().map(|p| p.permission.as_str()))?;
+            let conn = server.conn()?;
+
+            let permissions = std::mem::take(&mut create_group.permissions);
+            let db_group: InsertGroup = create_group.into();
+            let created_group = conn.transaction::<_, diesel::result::Error, _>(|| {
+                let created_group = db_group.insert(&conn)?;
+
+                let db_permissions: Vec<GroupPermission> = permissions
+                    .into_iter()
+                    .map(|p| GroupPermission {
+                        group_id: created_group.group_id,
+                        permission: p.permission,
+                        granted: p.granted,
+                    })
+                    .collect();
+                diesel::insert_into(schema::bpp_groups_permissions::table)
+                    .values(&db_permissions)
+                    .execute(&conn)?;
+                AuditChange::new(&actor, "create_group")
+                    .target(created_group.group_id)
+                    .after(group_with_permissions_snapshot(&created_group, &db_permissions))
+                    .save(&conn)?;
+
+                Ok(created_group)
+            });
+            let created_group = match created_group {
+                Ok(created_group) => created_group,
+                Err(e) if is_unique_violation(&e) => return Err(Status::already_exists("Group already exists")),
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(Status::internal("Failed to create group"));
+                }
+            };
+
+            let group = userservice_group(&created_group, &conn)?;
+            Ok(tonic::Response::new(group))
+        })
+        .await
     }
 
-    async fn get_rank(&self, request:tonic::Request<i32>) ->Result<tonic::Response<userservice::BppRank>,tonic::Status> {
-        let conn = self.database_pool.get().unwrap();
-        let rank = request.into_inner();
-        let rank = Rank::get_from_database(&rank, &conn);
+    async fn add_user_to_group(
+        &self,
+        request: tonic::Request<userservice::GroupMembership>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("add_user_to_group", &request)?;
+            let actor = actor_of(&request);
+            let membership = request.into_inner();
+            record_channel_id(&membership.channel_id);
+            let conn = server.conn()?;
+            validate_membership(&membership, &conn)?;
+
+            let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+                // The user already was a member if nothing was inserted, so nothing changed
+                if GroupUser::add(membership.group_id, &membership.channel_id, &conn)? > 0 {
+                    AuditChange::new(&actor, "add_user_to_group")
+                        .target(&membership.channel_id)
+                        .after(serde_json::json!({ "group_id": membership.group_id }))
+                        .save(&conn)?;
+                }
+                Ok(())
+            });
+            if let Err(e) = result {
+                error!("{}", e);
+                return Err(tonic::Status::internal("Failed to add user to group"));
+            }
 
-        if rank.is_none() {
-            return Err(Status::not_found("Rank not found"));
-        }
-        let rank = rank.unwrap();
+            Ok(tonic::Response::new(()))
+        })
+        .await
+    }
 
-        let hour_requirement = prost_types::Duration {
-            seconds: rank.hour_requirement_seconds,
-            nanos: rank.hour_requirement_nanos,
-        };
-        let rank = userservice::BppRank {
-            rank_id: rank.rank_id,
-            rank_name: rank.rank_name,
-            rank_sorting: rank.rank_sorting,
-            hour_requirement: Some(hour_requirement),
+    async fn remove_user_from_group(
+        &self,
+        request: tonic::Request<userservice::GroupMembership>,
+    ) -> Result<tonic::Response<()>, tonic::Status> {
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("remove_user_from_group", &request)?;
+            let actor = actor_of(&request);
+            let membership = request.into_inner();
+            record_channel_id(&membership.channel_id);
+            let conn = server.conn()?;
+            validate_membership(&membership, &conn)?;
+
+            let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+                let removed = GroupUser::remove(membership.group_id, &membership.channel_id, &conn)?;
+                if removed > 0 {
+                    AuditChange::new(&actor, "remove_user_from_group")
+                        .target(&membership.channel_id)
+                        .before(serde_json::json!({ "group_id": membership.group_id }))
+                        .save(&conn)?;
+                }
+                Ok(removed)
+            });
+            match result {
+                Ok(0) => Err(tonic::Status::not_found("User is not a member of the group")),
+                Ok(_) => Ok(tonic::Response::new(())),
+                Err(e) => {
+                    error!("{}", e);
+                    Err(tonic::Status::internal("Failed to remove user from group"))
+                }
+            }
+        })
+        .await
+    }
+
+    async fn get_user_groups(
+        &self,
+        request: tonic::Request<String>,
+    ) -> Result<tonic::Response<userservice::BppGroups>, tonic::Status> {
+        self.limit_rate(&request)?;
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            let user_id = request.into_inner();
+            record_channel_id(&user_id);
+            let conn = server.conn()?;
+
+            if !user_exists(&user_id, &conn)? {
+                return Err(tonic::Status::not_found("User not found"));
+            }
+            let groups = Group::find_for_user(&user_id, &conn).and_then(|groups| {
+                groups
+                    .iter()
+                    .map(|group| group.try_to_userservice_group(&conn))
+                    .collect::<diesel::QueryResult<Vec<BppGroup>>>()
+            });
+            let groups = match groups {
+                Ok(groups) => groups,
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(tonic::Status::internal("Failed to load groups of user"));
+                }
+            };
+            let count = groups.len() as i32;
+            Ok(tonic::Response::new(userservice::BppGroups { groups, count }))
+        })
+        .await
+    }
+
+    async fn get_group_members(
+        &self,
+        request: tonic::Request<userservice::GroupMembersRequest>,
+    ) -> Result<tonic::Response<userservice::BppUsers>, tonic::Status> {
+        self.limit_rate(&request)?;
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            let members_request = request.into_inner();
+            let conn = server.conn()?;
+
+            if Group::get_from_database(&members_request.group_id, &conn).is_none() {
+                return Err(tonic::Status::not_found("Group not found"));
+            }
+            let members = User::get_group_members(
+                members_request.group_id,
+                members_request.limit,
+                members_request.offset,
+                &conn,
+            )
+            .and_then(|(members, count)| Ok((User::to_userservice_users(&members, &conn)?, count)));
+            match members {
+                Ok((users, count)) => Ok(tonic::Response::new(userservice::BppUsers {
+                    users,
+                    count: count as i32,
+                    next_cursor: String::new(),
+                })),
+                Err(e) => {
+                    error!("{}", e);
+                    Err(tonic::Status::internal("Failed to load members of group"))
+                }
+            }
+        })
+        .await
+    }
+
+    type SubscribeRankUpsStream =
+        Pin<Box<dyn Stream<Item = Result<RankUpEvent, Status>> + Send + Sync + 'static>>;
+
+    async fn subscribe_rank_ups(
+        &self,
+        _request: tonic::Request<()>,
+    ) -> Result<tonic::Response<Self::SubscribeRankUpsStream>, tonic::Status> {
+        let mut rank_ups = self.rank_ups.subscribe();
+        let shutdown = self.shutdown.clone();
+
+        // The stream ends on shutdown, otherwise the server would wait for subscribers forever
+        let stream = async_stream::stream! {
+            loop {
+                tokio::select! {
+                    event = rank_ups.recv() => match event {
+                        Ok(event) => yield Ok(event),
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            warn!("Rank-up subscriber is too slow, skipped {} events", skipped);
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    _ = wait_for_shutdown(shutdown.clone()) => break,
+                }
+            }
         };
-        return Ok(tonic::Response::new(rank));
+
+        Ok(tonic::Response::new(Box::pin(stream)))
+    }
+
+    async fn get_rank(&self, request:tonic::Request<i32>) ->Result<tonic::Response<userservice::BppRank>,tonic::Status> {
+        self.limit_rate(&request)?;
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            let conn = server.conn()?;
+            let rank = request.into_inner();
+            let rank = match Rank::get_from_database(&rank, &conn) {
+                Some(rank) => rank.to_userservice_rank(),
+                None => return Err(Status::not_found("Rank not found")),
+            };
+            Ok(tonic::Response::new(rank))
+        })
+        .await
     }
 
     async fn get_ranks(
         &self,
-        _: tonic::Request<()>,
+        request: tonic::Request<()>,
     ) -> Result<tonic::Response<userservice::BppRanks>, tonic::Status> {
-        let conn = self.database_pool.get().unwrap();
-        use schema::bpp_ranks::dsl::*;
-        let ranks = bpp_ranks
-            .order(rank_sorting.desc())
-            .load::<Rank>(&conn)
-            .unwrap();
-        let ranks: Vec<userservice::BppRank> = ranks
-            .into_iter()
-            .map(|rank| {
-                let hour_requirement = prost_types::Duration {
-                    seconds: rank.hour_requirement_seconds,
-                    nanos: rank.hour_requirement_nanos,
-                };
+        self.limit_rate(&request)?;
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            let conn = server.conn()?;
+            use schema::bpp_ranks::dsl::*;
+            let ranks = match bpp_ranks
+                .order((hour_requirement_seconds.asc(), hour_requirement_nanos.asc()))
+                .load::<Rank>(&conn)
+            {
+                Ok(ranks) => ranks,
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(Status::internal("Failed to load ranks"));
+                }
+            };
+            let ranks: Vec<userservice::BppRank> = ranks
+                .iter()
+                .map(|rank| rank.to_userservice_rank())
+                .collect();
+            let count = ranks.len() as i32;
+            Ok(tonic::Response::new(userservice::BppRanks { ranks, count }))
+        })
+        .await
+    }
 
-                userservice::BppRank {
-                    rank_id: rank.rank_id,
-                    rank_name: rank.rank_name,
-                    rank_sorting: rank.rank_sorting,
-                    hour_requirement: Some(hour_requirement),
+    async fn get_rank_for_user(
+        &self,
+        request: tonic::Request<String>,
+    ) -> Result<tonic::Response<userservice::UserRank>, tonic::Status> {
+        self.limit_rate(&request)?;
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            let user_id = request.into_inner();
+            record_channel_id(&user_id);
+            let conn = server.conn()?;
+
+            let user = match User::find_active(&user_id, &conn) {
+                Ok(Some(user)) => user,
+                Ok(None) => return Err(Status::not_found("User not found")),
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(Status::internal("Failed to get user"));
                 }
-            })
-            .collect();
-        let count = ranks.len() as i32;
-        return Ok(tonic::Response::new(userservice::BppRanks { ranks, count }));
+            };
+            let ranks = user.find_active_rank(&conn).and_then(|rank| {
+                let next_rank = user.find_next_rank(rank.as_ref(), &conn)?;
+                Ok((rank, next_rank))
+            });
+            let (rank, next_rank) = match ranks {
+                Ok(ranks) => ranks,
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(Status::internal("Failed to get rank of user"));
+                }
+            };
+
+            let hours_remaining = next_rank.as_ref().map(|next_rank| {
+                let remaining_nanos = (next_rank.hour_requirement_seconds as i128 * 1_000_000_000
+                    + next_rank.hour_requirement_nanos as i128)
+                    - (user.hours_seconds as i128 * 1_000_000_000 + user.hours_nanos as i128);
+                let remaining_nanos = remaining_nanos.max(0);
+                prost_types::Duration {
+                    seconds: (remaining_nanos / 1_000_000_000) as i64,
+                    nanos: (remaining_nanos % 1_000_000_000) as i32,
+                }
+            });
+            Ok(tonic::Response::new(userservice::UserRank {
+                rank: rank.as_ref().map(Rank::to_userservice_rank),
+                next_rank: next_rank.as_ref().map(Rank::to_userservice_rank),
+                hours_remaining,
+            }))
+        })
+        .await
     }
 
     async fn update_rank(
         &self,
         request: tonic::Request<userservice::BppRank>,
     ) -> Result<tonic::Response<userservice::BppRank>, tonic::Status> {
-        let rank = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        let db_rank: Rank = (&rank).into();
-        db_rank.save_to_database(&conn).unwrap();
-        return Ok(tonic::Response::new(rank));
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("update_rank", &request)?;
+            let actor = actor_of(&request);
+            let rank = request.into_inner();
+            validate_rank(&rank.hour_requirement, rank.payout_multiplier)?;
+            let conn = server.conn()?;
+
+            let mut rejection = None;
+            let result = conn.transaction::<_, diesel::result::Error, _>(|| match save_rank(&actor, "update_rank", &rank.into(), &conn)? {
+                Ok(db_rank) => Ok(db_rank),
+                Err(status) => {
+                    rejection = Some(status);
+                    Err(diesel::result::Error::RollbackTransaction)
+                }
+            });
+            match (result, rejection) {
+                (Ok(db_rank), _) => Ok(tonic::Response::new(db_rank.to_userservice_rank())),
+                (Err(_), Some(rejection)) => Err(rejection),
+                (Err(e), None) => {
+                    error!("{}", e);
+                    Err(Status::internal("Failed to update rank"))
+                }
+            }
+        })
+        .await
     }
 
     async fn update_ranks(
         &self,
         request: tonic::Request<userservice::BppRanks>,
     ) -> Result<tonic::Response<userservice::BppRanks>, tonic::Status> {
-        let ranks = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        for rank in &ranks.ranks {
-            let db_rank: Rank = rank.into();
-            db_rank.save_to_database(&conn).unwrap();
-        }
-        return Ok(tonic::Response::new(ranks));
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("update_ranks", &request)?;
+            let actor = actor_of(&request);
+            let ranks = request.into_inner().ranks;
+            for rank in &ranks {
+                validate_rank(&rank.hour_requirement, rank.payout_multiplier)?;
+            }
+            let conn = server.conn()?;
+
+            // A rejected rank rolls back the whole batch, earlier ones count for the collisions
+            let mut rejection = None;
+            let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+                let mut updated_ranks = Vec::with_capacity(ranks.len());
+                for rank in &ranks {
+                    match save_rank(&actor, "update_ranks", &rank.into(), &conn)? {
+                        Ok(db_rank) => updated_ranks.push(db_rank.to_userservice_rank()),
+                        Err(status) => {
+                            rejection = Some(status);
+                            return Err(diesel::result::Error::RollbackTransaction);
+                        }
+                    }
+                }
+                Ok(updated_ranks)
+            });
+            match (result, rejection) {
+                (Ok(ranks), _) => {
+                    let count = ranks.len() as i32;
+                    Ok(tonic::Response::new(userservice::BppRanks { ranks, count }))
+                }
+                (Err(_), Some(rejection)) => Err(rejection),
+                (Err(e), None) => {
+                    error!("{}", e);
+                    Err(Status::internal("Failed to update ranks"))
+                }
+            }
+        })
+        .await
     }
 
     async fn delete_rank(
         &self,
         request: tonic::Request<i32>,
     ) -> Result<tonic::Response<()>, tonic::Status> {
-        let id = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        use schema::bpp_ranks::dsl::*;
-        diesel::delete(bpp_ranks.filter(rank_id.eq(id)))
-            .execute(&conn)
-            .unwrap();
-        return Ok(tonic::Response::new(()));
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("delete_rank", &request)?;
+            let actor = actor_of(&request);
+            let id = request.into_inner();
+            let conn = server.conn()?;
+            let before = Rank::get_from_database(&id, &conn);
+            use schema::bpp_ranks::dsl::*;
+            let deleted = conn.transaction::<_, diesel::result::Error, _>(|| {
+                let deleted = diesel::delete(bpp_ranks.filter(rank_id.eq(id))).execute(&conn)?;
+                if deleted > 0 {
+                    let mut change = AuditChange::new(&actor, "delete_rank").target(id);
+                    if let Some(before) = &before {
+                        change = change.before(rank_snapshot(before));
+                    }
+                    change.save(&conn)?;
+                }
+                Ok(deleted)
+            });
+            match deleted {
+                Ok(0) => Err(Status::not_found("Rank not found")),
+                Ok(_) => Ok(tonic::Response::new(())),
+                Err(e) => {
+                    error!("{}", e);
+                    Err(Status::internal("Failed to delete rank"))
+                }
+            }
+        })
+        .await
     }
 
     async fn delete_ranks(
         &self,
         request: tonic::Request<userservice::BppRankIds>,
     ) -> Result<tonic::Response<()>, tonic::Status> {
-        let rank_ids = request.into_inner().ranks;
-        let conn = self.database_pool.get().unwrap();
-        use schema::bpp_ranks::dsl::*;
-        diesel::delete(bpp_ranks.filter(rank_id.eq_any(rank_ids)))
-            .execute(&conn)
-            .unwrap();
-        return Ok(tonic::Response::new(()));
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("delete_ranks", &request)?;
+            let actor = actor_of(&request);
+            let rank_ids = request.into_inner().ranks;
+            let conn = server.conn()?;
+            use schema::bpp_ranks::dsl::*;
+            let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+                let deleted_ranks = bpp_ranks.filter(rank_id.eq_any(&rank_ids)).load::<Rank>(&conn)?;
+                diesel::delete(bpp_ranks.filter(rank_id.eq_any(&rank_ids))).execute(&conn)?;
+                for deleted_rank in &deleted_ranks {
+                    AuditChange::new(&actor, "delete_ranks")
+                        .target(deleted_rank.rank_id)
+                        .before(rank_snapshot(deleted_rank))
+                        .save(&conn)?;
+                }
+                Ok(())
+            });
+            if let Err(e) = result {
+                error!("{}", e);
+                return Err(Status::internal("Failed to delete ranks"));
+            }
+            Ok(tonic::Response::new(()))
+        })
+        .await
     }
 
     async fn create_rank(
         &self,
         request: tonic::Request<userservice::CreateBppRank>,
     ) -> Result<tonic::Response<userservice::BppRank>, tonic::Status> {
-        let create_rank = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        let db_rank: InsertRank = create_rank.into();
-        let created_rank = db_rank.save_to_database(&conn).unwrap();
-        let hour_requirement = prost_types::Duration {
-            seconds: created_rank.hour_requirement_seconds,
-            nanos: created_rank.hour_requirement_nanos,
-        };
-        let rank = userservice::BppRank {
-            rank_id: created_rank.rank_id,
-            rank_name: created_rank.rank_name,
-            rank_sorting: created_rank.rank_sorting,
-            hour_requirement: Some(hour_requirement),
-        };
-        return Ok(tonic::Response::new(rank));
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("create_rank", &request)?;
+            let actor = actor_of(&request);
+            let create_rank = request.into_inner();
+            validate_rank(&create_rank.hour_requirement, create_rank.payout_multiplier)?;
+
+            let conn = server.conn()?;
+            let db_rank: InsertRank = create_rank.into();
+            if Rank::get_by_hour_requirement(
+                db_rank.hour_requirement_seconds,
+                db_rank.hour_requirement_nanos,
+                &conn,
+            )
+            .is_some()
+            {
+                return Err(Status::already_exists(
+                    "A rank with this hour requirement already exists",
+                ));
+            }
+
+            let created_rank = conn.transaction::<_, diesel::result::Error, _>(|| {
+                let created_rank = db_rank
+                    .save_to_database(&conn)
+                    .ok_or(diesel::result::Error::RollbackTransaction)?;
+                AuditChange::new(&actor, "create_rank")
+                    .target(created_rank.rank_id)
+                    .after(rank_snapshot(&created_rank))
+                    .save(&conn)?;
+                Ok(created_rank)
+            });
+            let created_rank = match created_rank {
+                Ok(created_rank) => created_rank,
+                Err(e) => {
+                    error!("{}", e);
+                    return Err(Status::internal("Failed to create rank"));
+                }
+            };
+            let rank = created_rank.to_userservice_rank();
+            Ok(tonic::Response::new(rank))
+        })
+        .await
     }
 
     async fn user_grant_permission(
         &self,
         request: tonic::Request<userservice::UserPermission>,
     ) -> Result<tonic::Response<()>, tonic::Status> {
-        let granted_permission = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        use schema::bpp_users_permissions::dsl::*;
-        let db_permission = models::UserPermission {
-            channel_id: granted_permission.channel_id,
-            permission: granted_permission.permission,
-            granted: true
-        };
-        diesel::insert_into(bpp_users_permissions)
-            .values(&db_permission)
-            .execute(&conn)
-            .unwrap();
-        return Ok(tonic::Response::new(()));
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("user_grant_permission", &request)?;
+            let actor = actor_of(&request);
+            let granted_permission = request.into_inner();
+            record_channel_id(&granted_permission.channel_id);
+            server.validate_permissions(std::iter::once(granted_permission.permission.as_str()))?;
+            let conn = server.conn()?;
+            if !user_exists(&granted_permission.channel_id, &conn)? {
+                return Err(tonic::Status::not_found("User not found"));
+            }
+            let expires_at = match &granted_permission.expires_after {
+                Some(expires_after) => {
+                    let expires_at = std::time::Duration::try_from(expires_after.clone())
+                        .ok()
+                        .and_then(|expires_after| chrono::Duration::from_std(expires_after).ok())
+                        .filter(|expires_after| *expires_after > chrono::Duration::zero())
+                        .and_then(|expires_after| Utc::now().naive_utc().checked_add_signed(expires_after));
+                    match expires_at {
+                        Some(expires_at) => Some(expires_at),
+                        None => return Err(Status::invalid_argument("The permission must expire in the future")),
+                    }
+                }
+                None => None,
+            };
+
+            // Granting a permission the user already has directly is not an error
+            let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+                models::UserPermission::grant(
+                    &granted_permission.channel_id,
+                    &granted_permission.permission,
+                    expires_at,
+                    &conn,
+                )?;
+                AuditChange::new(&actor, "user_grant_permission")
+                    .target(&granted_permission.channel_id)
+                    .after(serde_json::json!({
+                        "permission": granted_permission.permission,
+                        "granted": true,
+                        "expires_at": expires_at.map(|expires_at| expires_at.to_string()),
+                    }))
+                    .save(&conn)?;
+                Ok(())
+            });
+            if let Err(e) = result {
+                error!("{}", e);
+                return Err(tonic::Status::internal("Failed to grant permission"));
+            }
+            Ok(tonic::Response::new(()))
+        })
+        .await
     }
 
     async fn user_revoke_permisison(
         &self,
         request: tonic::Request<userservice::UserPermission>,
     ) -> Result<tonic::Response<()>, tonic::Status> {
-        let revoked_permission = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        use schema::bpp_users_permissions::dsl::*;
-        let db_permission = models::UserPermission {
-            channel_id: revoked_permission.channel_id,
-            permission: revoked_permission.permission,
-            granted: false
-        };
-        diesel::insert_into(bpp_users_permissions)
-            .values(&db_permission)
-            .execute(&conn)
-            .unwrap();
-        return Ok(tonic::Response::new(()));
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("user_revoke_permisison", &request)?;
+            let actor = actor_of(&request);
+            let revoked_permission = request.into_inner();
+            record_channel_id(&revoked_permission.channel_id);
+            let conn = server.conn()?;
+
+            // Only the direct permission is removed, permissions from groups stay untouched
+            let result = conn.transaction::<_, diesel::result::Error, _>(|| {
+                let removed = models::UserPermission::remove(
+                    &revoked_permission.channel_id,
+                    &revoked_permission.permission,
+                    &conn,
+                )?;
+                if removed > 0 {
+                    AuditChange::new(&actor, "user_revoke_permisison")
+                        .target(&revoked_permission.channel_id)
+                        .before(serde_json::json!({ "permission": revoked_permission.permission }))
+                        .save(&conn)?;
+                }
+                Ok(removed)
+            });
+            match result {
+                Ok(0) => Err(tonic::Status::not_found("User does not have this permission directly")),
+                Ok(_) => Ok(tonic::Response::new(())),
+                Err(e) => {
+                    error!("{}", e);
+                    Err(tonic::Status::internal("Failed to revoke permission"))
+                }
+            }
+        })
+        .await
     }
 
     async fn group_grant_permission(
         &self,
         request: tonic::Request<userservice::GroupPermission>,
     ) -> Result<tonic::Response<()>, tonic::Status> {
-        let granted_permission = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        use schema::bpp_groups_permissions::dsl::*;
-        let db_permission = models::GroupPermission {
-            group_id: granted_permission.group_id,
-            permission: granted_permission.permission,
-            granted: true
-        };
-        diesel::insert_into(bpp_groups_permissions)
-            .values(&db_permission)
-            .execute(&conn)
-            .unwrap();
-        return Ok(tonic::Response::new(()));
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("group_grant_permission", &request)?;
+            let actor = actor_of(&request);
+            let granted_permission = request.into_inner();
+            server.validate_permissions(std::iter::once(granted_permission.permission.as_str()))?;
+            let db_permission = models::GroupPermission {
+                group_id: granted_permission.group_id,
+                permission: granted_permission.permission,
+                granted: true,
+            };
+            server.save_group_permission(&actor, "group_grant_permission", &db_permission)?;
+            Ok(tonic::Response::new(()))
+        })
+        .await
     }
 
     async fn group_revoke_permission(
         &self,
         request: tonic::Request<userservice::GroupPermission>,
     ) -> Result<tonic::Response<()>, tonic::Status> {
-        let revoked_permission = request.into_inner();
-        let conn = self.database_pool.get().unwrap();
-        use schema::bpp_groups_permissions::dsl::*;
-        let db_permission = models::GroupPermission {
-            group_id: revoked_permission.group_id,
-            permission: revoked_permission.permission,
-            granted: false
-        };
-        diesel::insert_into(bpp_groups_permissions)
-            .values(&db_permission)
-            .execute(&conn)
-            .unwrap();
-        return Ok(tonic::Response::new(()));
+        let server = self.clone();
+        run_blocking(self.database_timeout, move || {
+            server.authorize("group_revoke_permission", &request)?;
+            let actor = actor_of(&request);
+            let revoked_permission = request.into_inner();
+            let db_permission = models::GroupPermission {
+                group_id: revoked_permission.group_id,
+                permission: revoked_permission.permission,
+                granted: false,
+            };
+            server.save_group_permission(&actor, "group_revoke_permission", &db_permission)?;
+            Ok(tonic::Response::new(()))
+        })
+        .await
     }
 }
 
@@ -669,13 +2646,62 @@ impl UserService for UserServer {
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
 
-    setup_log(env::var_os("DEBUG").is_some());
+    let log_directives = env::var("LOG_LEVEL").or_else(|_| env::var("RUST_LOG")).ok();
+    let log_format = LogFormat::parse(env::var("LOG_FORMAT").ok().as_deref());
+    setup_log(
+        env::var_os("DEBUG").is_some(),
+        log_directives.as_deref(),
+        *log_format.as_ref().unwrap_or(&LogFormat::Text),
+    );
+    if let Err(e) = log_format {
+        warn!("{}, logging as text", e);
+    }
     debug!("Debug mode activated!");
+    if let Err(e) = setup_tracing(env::var("JAEGER_AGENT_ENDPOINT").ok()) {
+        error!("Failed to set up tracing: {}", e);
+        std::process::exit(1);
+    }
+
+    // `userservice-server migrate` only runs the migrations, so a deploy can run them once before
+    // starting the replicas
+    let migrate_only = match env::args().nth(1).as_deref() {
+        None => false,
+        Some("migrate") => true,
+        Some(command) => {
+            error!("Unknown command {}, the only command is migrate", command);
+            std::process::exit(1);
+        }
+    };
+    let run_migrations = migrate_only || !matches!(env::var("RUN_MIGRATIONS").as_deref(), Ok("false"));
+    if !run_migrations {
+        info!("RUN_MIGRATIONS is false, expecting the database to be migrated already");
+    }
 
     info!("Loading settings...");
-    let _ = Settings::new()?;
+    let settings = Settings::new()?;
+    if settings.dry_run {
+        warn!("DRY_RUN is set, chat activity is only logged and not saved");
+    }
 
-    let pool = connect_to_database();
+    let database_timeout = std::time::Duration::from_secs(settings.database_timeout_seconds.max(1) as u64);
+    let connection_settings = ConnectionSettings {
+        statement_timeout_ms: settings.statement_timeout_ms.max(0) as u32,
+        application_name: application_name(
+            env::var("INSTANCE_NAME").or_else(|_| env::var("HOSTNAME")).ok().as_deref(),
+        ),
+    };
+    let pool = match connect_to_database(database_timeout, connection_settings, run_migrations) {
+        Ok(pool) => pool,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if migrate_only {
+        info!("Migrated the database");
+        let _ = tokio::task::spawn_blocking(shutdown_tracing).await;
+        return Ok(());
+    }
 
     let youtube_address = env::var("YTS_GRPC_ADDRESS").expect("YTS_GRPC_ADDRESS must be set");
     let userservice_address = env::var("US_GRPC_ADDRESS");
@@ -684,21 +2710,157 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     } else {
         userservice_address.unwrap().parse()?
     };
+    let service_token = env::var("SERVICE_TOKEN").ok().filter(|token| !token.is_empty());
+    if service_token.is_none() {
+        warn!("SERVICE_TOKEN is not set, requests are accepted without authentication");
+    }
+    let metrics_address: SocketAddr = env::var("METRICS_ADDRESS")
+        .unwrap_or_else(|_| "0.0.0.0:9184".to_string())
+        .parse()?;
+
+    let mut server = tonic::transport::Server::builder();
+    let mut tls_enabled = false;
+    match tls_config_from_env() {
+        Ok(Some(tls_config)) => {
+            server = server.tls_config(tls_config)?;
+            tls_enabled = true;
+            info!("Serving the userservice over TLS");
+        }
+        Ok(None) => warn!("TLS_CERT_PATH and TLS_KEY_PATH are not set, serving without TLS"),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
 
-    let mut youtube_client = YouTubeServiceClient::connect(youtube_address).await?;
-    info!("Connected to youtubeservice! Time to go on a hunt!");
-
+    let youtube_channel = match youtube_channel(&youtube_address) {
+        Ok(channel) => channel,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut youtube_client = YouTubeServiceClient::new(youtube_channel);
+    info!("Using youtubeservice at {}, time to go on a hunt!", youtube_address);
+
+    let authorize_callers = matches!(env::var("AUTHORIZE_CALLERS").as_deref(), Ok("true"));
+    log_startup_summary(&StartupSummary {
+        listen_address: userservice_address,
+        metrics_address,
+        youtube_addresses: &youtube_address,
+        settings: &settings,
+        tls_enabled,
+        token_auth_enabled: service_token.is_some(),
+        authorize_callers,
+    });
+
+    let (shutdown_trigger, shutdown) = listen_for_shutdown();
+    let (rank_ups, _) = broadcast::channel(RANK_UP_CHANNEL_CAPACITY);
     let service = UserServer {
-        database_pool: pool.clone()
+        database_pool: pool.clone(),
+        database_timeout,
+        rank_ups: rank_ups.clone(),
+        shutdown: shutdown.clone(),
+        authorize_callers,
+        rate_limiter: Arc::new(RateLimiter::new(
+            settings.rate_limit_burst.max(0) as u32,
+            settings.rate_limit_per_second.max(0) as u32,
+        )),
+        default_group: settings.default_group.clone(),
+        permission_registry: Arc::new(PermissionRegistry::new(&settings.known_permissions)),
+        active_window: chrono::Duration::seconds(settings.active_time as i64),
+    };
+
+    let mut scheduler = Scheduler::default();
+    scheduler.register(
+        "remove_expired_permissions",
+        std::time::Duration::from_secs(settings.expiry_cleanup_seconds.max(1) as u64),
+        remove_expired_permissions,
+    );
+
+    let youtube_connected = Arc::new(AtomicBool::new(false));
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+
+    // The other tasks only stop on shutdown, so a server that stops on its own has to trigger it
+    let server_shutdown = shutdown.clone();
+    let serve = async move {
+        let result = server
+            .trace_fn(|request| {
+                record_request(request.uri().path());
+                request_span(request)
+            })
+            .add_service(health_service)
+            .add_service(UserServiceServer::with_interceptor(service, TokenAuth::new(service_token)))
+            .serve_with_shutdown(userservice_address, wait_for_shutdown(server_shutdown))
+            .await;
+        shutdown_trigger.trigger();
+        result
     };
 
     info!("Starting message fetching and userservice");
-    let (_, _) = tokio::join!(
-        fetch_users_from_messages(&mut youtube_client, &pool),
-        tonic::transport::Server::builder()
-            .add_service(UserServiceServer::new(service))
-            .serve(userservice_address)
+    let (_, _, _, _, server_result) = tokio::join!(
+        ingest_messages(
+            &mut youtube_client,
+            &pool,
+            &settings,
+            youtube_connected.clone(),
+            rank_ups,
+            shutdown.clone()
+        ),
+        report_health(
+            health_reporter,
+            pool.clone(),
+            youtube_connected,
+            shutdown.clone()
+        ),
+        serve_metrics(metrics_address, pool.clone(), shutdown.clone()),
+        scheduler.run(pool.clone(), shutdown),
+        serve
     );
+    if let Err(e) = &server_result {
+        error!("userservice failed: {}", e);
+    }
 
+    // All in-flight requests are done at this point, so the pool can be closed
+    drop(pool);
+    // Flushing the remaining spans blocks, so it must not happen on the runtime
+    let _ = tokio::task::spawn_blocking(shutdown_tracing).await;
+    info!("Shutdown complete");
+
+    // A failed server has to make the process exit with an error
+    server_result?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANNEL_ID: &str = "UCabcdefghijklmnopqrstuv";
+
+    fn imported(channel_id: &str, seconds: i64, nanos: i32, money: f64) -> BppUser {
+        BppUser {
+            channel_id: channel_id.to_string(),
+            display_name: "Lumi".to_string(),
+            hours: Some(prost_types::Duration { seconds, nanos }),
+            money,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn imported_channel_ids_are_normalized() {
+        let row = imported(&format!("  {} ", CHANNEL_ID), 3600, 0, 1.5);
+        let user = import_row(&row).expect("the row should be valid");
+        assert_eq!(user.channel_id, CHANNEL_ID);
+        assert_eq!((user.hours_seconds, user.money), (3600, 1.5));
+    }
+
+    #[test]
+    fn invalid_rows_are_rejected() {
+        assert!(import_row(&imported("UC123", 0, 0, 0.0)).is_err());
+        assert!(import_row(&imported(CHANNEL_ID, -1, 0, 0.0)).is_err());
+        assert!(import_row(&imported(CHANNEL_ID, 0, 0, -0.5)).is_err());
+        assert!(import_row(&imported(CHANNEL_ID, 0, 1_000_000_000, 0.0)).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use std::env;
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
@@ -6,21 +7,87 @@ use config::{ConfigError, Config};
 use config::File as ConfigFile;
 use log::debug;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Settings {
     pub default_payout: i32,
-    pub active_time: i32
+    pub active_time: i32,
+    /// How long chat messages are collected before they're written to the database
+    pub message_buffer_ms: i32,
+    /// The most seconds a user can be credited for at once, no matter how long the gap was
+    pub max_credit_seconds: i32,
+    /// After how many seconds without a message a connected stream is reported as stalled
+    pub stall_warning_seconds: i32,
+    /// How many characters a message needs to count as activity, shorter ones only update the
+    /// display name. Set with `MIN_MESSAGE_LENGTH`.
+    pub min_message_length: i32,
+    /// Multiplies the payout of channel members on top of their rank, set with `MEMBER_MULTIPLIER`
+    pub member_multiplier: f64,
+    /// Only pay money to users who chatted within the active window before their latest messages,
+    /// set with `MONEY_REQUIRES_CHAT=true`
+    pub money_requires_chat: bool,
+    /// How many seconds have to pass between two payouts of a user, the time credited in between
+    /// is paid with the next payout. Set with `PAYOUT_COOLDOWN_SECONDS`.
+    pub payout_cooldown_seconds: i32,
+    /// The name of the group every new user joins, none if empty. Set with `DEFAULT_GROUP`.
+    pub default_group: String,
+    /// The permissions of the other services, which can be stored on top of the ones of the RPCs.
+    /// Set with a comma separated `KNOWN_PERMISSIONS`.
+    pub known_permissions: Vec<String>,
+    /// Keep the display names users had before they renamed themselves, set with
+    /// `TRACK_PREVIOUS_NAMES=true`
+    pub track_previous_names: bool,
+    /// How often permissions which expired are deleted
+    pub expiry_cleanup_seconds: i32,
+    /// How many read requests a client can make at once, set with `RATE_LIMIT_BURST`
+    pub rate_limit_burst: i32,
+    /// How many read requests per second a client can make over time, 0 turns the limit off.
+    /// Set with `RATE_LIMIT_PER_SECOND`.
+    pub rate_limit_per_second: i32,
+    /// How long an RPC waits for the database before failing, set with `DATABASE_TIMEOUT_SECONDS`
+    pub database_timeout_seconds: i32,
+    /// After how many milliseconds Postgres cancels a statement, 0 turns the limit off. Set with
+    /// `STATEMENT_TIMEOUT_MS`.
+    pub statement_timeout_ms: i32,
+    /// How long subscribing to youtubeservice may take, set with `YOUTUBE_TIMEOUT_SECONDS`
+    pub youtube_timeout_seconds: i32,
+    /// Only log what the ingest would change instead of saving it, set with `DRY_RUN`
+    #[serde(skip)]
+    pub dry_run: bool
 }
 
 impl Default for Settings {
     fn default() -> Settings {
         Settings {
             default_payout: 1,
-            active_time: 5 * 60
+            active_time: 5 * 60,
+            message_buffer_ms: 1000,
+            max_credit_seconds: 5 * 60,
+            stall_warning_seconds: 5 * 60,
+            min_message_length: 0,
+            member_multiplier: 1.0,
+            money_requires_chat: false,
+            payout_cooldown_seconds: 0,
+            default_group: String::new(),
+            known_permissions: Vec::new(),
+            track_previous_names: false,
+            expiry_cleanup_seconds: 60,
+            rate_limit_burst: 200,
+            rate_limit_per_second: 50,
+            database_timeout_seconds: 10,
+            statement_timeout_ms: 30_000,
+            youtube_timeout_seconds: 10,
+            dry_run: false
         }
     }
 }
 
+/// Parses the value of an environment variable holding a count, which can't be negative
+fn parse_count(variable: &str, value: &str) -> Result<i32, ConfigError> {
+    let parsed = value.parse::<i32>().ok().filter(|value| *value >= 0);
+    parsed.ok_or_else(|| ConfigError::Message(format!("{} must be a positive number, got \"{}\"", variable, value)))
+}
+
 impl Settings {
     /// Loads the configuration or, if it doesn't exist, creates a new one filled with defaults
     pub fn new() -> Result<Self, ConfigError> {
@@ -39,7 +106,81 @@ impl Settings {
         }
 
         s.merge(ConfigFile::with_name("config/userservice"))?;
-        s.try_into()
+
+        // Environment variables take precedence over the config file
+        if let Ok(active_window) = env::var("ACTIVE_WINDOW_SECONDS") {
+            let parsed_window = active_window.parse::<i32>().ok().filter(|seconds| *seconds >= 0);
+            let parsed_window = parsed_window.ok_or_else(|| {
+                ConfigError::Message(format!(
+                    "ACTIVE_WINDOW_SECONDS must be a positive number of seconds, got \"{}\"",
+                    active_window
+                ))
+            })?;
+            s.set("active_time", parsed_window as i64)?;
+        }
+        if let Ok(multiplier) = env::var("MEMBER_MULTIPLIER") {
+            let parsed = multiplier.parse::<f64>().ok().filter(|m| m.is_finite() && *m >= 0.0);
+            let parsed = parsed.ok_or_else(|| {
+                ConfigError::Message(format!(
+                    "MEMBER_MULTIPLIER must be a positive number, got \"{}\"",
+                    multiplier
+                ))
+            })?;
+            s.set("member_multiplier", parsed)?;
+        }
+        if let Ok(requires_chat) = env::var("MONEY_REQUIRES_CHAT") {
+            s.set("money_requires_chat", requires_chat == "true")?;
+        }
+        if let Ok(track_previous_names) = env::var("TRACK_PREVIOUS_NAMES") {
+            s.set("track_previous_names", track_previous_names == "true")?;
+        }
+        if let Ok(default_group) = env::var("DEFAULT_GROUP") {
+            s.set("default_group", default_group)?;
+        }
+        for (variable, key) in &[
+            ("MESSAGE_BUFFER_MS", "message_buffer_ms"),
+            ("MIN_MESSAGE_LENGTH", "min_message_length"),
+            ("PAYOUT_COOLDOWN_SECONDS", "payout_cooldown_seconds"),
+            ("RATE_LIMIT_BURST", "rate_limit_burst"),
+            ("RATE_LIMIT_PER_SECOND", "rate_limit_per_second"),
+            ("DATABASE_TIMEOUT_SECONDS", "database_timeout_seconds"),
+            ("STATEMENT_TIMEOUT_MS", "statement_timeout_ms"),
+            ("YOUTUBE_TIMEOUT_SECONDS", "youtube_timeout_seconds"),
+        ] {
+            if let Ok(value) = env::var(variable) {
+                s.set(key, parse_count(variable, &value)? as i64)?;
+            }
+        }
+
+        let mut settings: Settings = s.try_into()?;
+        settings.dry_run = env::var_os("DRY_RUN").is_some();
+        if let Ok(known_permissions) = env::var("KNOWN_PERMISSIONS") {
+            settings.known_permissions = known_permissions
+                .split(',')
+                .map(|permission| permission.trim().to_string())
+                .filter(|permission| !permission.is_empty())
+                .collect();
+        }
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Rejects settings the ingest can't run with, a zero interval panics and a negative one
+    /// would wrap around to a huge one
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.message_buffer_ms <= 0 {
+            return Err(ConfigError::Message(format!(
+                "message_buffer_ms must be above 0, got {}",
+                self.message_buffer_ms
+            )));
+        }
+        if self.stall_warning_seconds <= 0 {
+            return Err(ConfigError::Message(format!(
+                "stall_warning_seconds must be above 0, got {}",
+                self.stall_warning_seconds
+            )));
+        }
+        Ok(())
     }
 
     /// Saves the configuration to the file
@@ -49,4 +190,45 @@ impl Settings {
 
         Ok(())
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_must_be_non_negative_numbers() {
+        assert_eq!(parse_count("RATE_LIMIT_BURST", "200").ok(), Some(200));
+        assert_eq!(parse_count("RATE_LIMIT_BURST", "0").ok(), Some(0));
+        assert!(parse_count("RATE_LIMIT_BURST", "-1").is_err());
+        assert!(parse_count("RATE_LIMIT_BURST", "many").is_err());
+        assert!(parse_count("RATE_LIMIT_BURST", "").is_err());
+    }
+
+    #[test]
+    fn default_settings_are_valid() {
+        assert!(Settings::default().validate().is_ok());
+    }
+
+    #[test]
+    fn message_buffer_must_be_positive() {
+        for message_buffer_ms in &[0, -1] {
+            let settings = Settings {
+                message_buffer_ms: *message_buffer_ms,
+                ..Settings::default()
+            };
+            assert!(settings.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn stall_warning_must_be_positive() {
+        for stall_warning_seconds in &[0, -300] {
+            let settings = Settings {
+                stall_warning_seconds: *stall_warning_seconds,
+                ..Settings::default()
+            };
+            assert!(settings.validate().is_err());
+        }
+    }
 }
\ No newline at end of file
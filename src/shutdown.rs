@@ -0,0 +1,56 @@
+use std::sync::Arc;
+
+use log::info;
+use tokio::sync::watch;
+
+/// Waits until the process is asked to stop with SIGTERM or Ctrl+C
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sigterm = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+        tokio::select! {
+            _ = sigterm.recv() => info!("Received SIGTERM, shutting down..."),
+            _ = tokio::signal::ctrl_c() => info!("Received Ctrl+C, shutting down..."),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        info!("Received Ctrl+C, shutting down...");
+    }
+}
+
+/// Requests shutdown from inside the service, e.g. when the gRPC server stopped on its own
+#[derive(Clone)]
+pub struct ShutdownTrigger(Arc<watch::Sender<bool>>);
+
+impl ShutdownTrigger {
+    pub fn trigger(&self) {
+        let _ = self.0.send(true);
+    }
+}
+
+/// Spawns the signal handler and returns a receiver which turns `true` once shutdown was requested,
+/// along with a trigger to request it without a signal
+pub fn listen_for_shutdown() -> (ShutdownTrigger, watch::Receiver<bool>) {
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let trigger = ShutdownTrigger(Arc::new(shutdown_tx));
+    let signal_trigger = trigger.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        signal_trigger.trigger();
+    });
+
+    (trigger, shutdown_rx)
+}
+
+/// Resolves once shutdown was requested
+pub async fn wait_for_shutdown(mut shutdown: watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            return;
+        }
+    }
+}
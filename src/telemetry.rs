@@ -0,0 +1,58 @@
+use std::error::Error;
+
+use log::info;
+use opentelemetry::global;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry_http::HeaderExtractor;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+
+/// Sets up exporting spans to a Jaeger agent
+///
+/// Spans are only exported if `JAEGER_AGENT_ENDPOINT` is set, otherwise they're discarded.
+pub fn setup_tracing(jaeger_agent_endpoint: Option<String>) -> Result<(), Box<dyn Error>> {
+    let jaeger_agent_endpoint = match jaeger_agent_endpoint {
+        Some(endpoint) => endpoint,
+        None => return Ok(()),
+    };
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let tracer = opentelemetry_jaeger::new_pipeline()
+        .with_service_name("userservice")
+        .with_agent_endpoint(jaeger_agent_endpoint.as_str())
+        .install_batch(opentelemetry::runtime::Tokio)?;
+    let subscriber =
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    tracing::subscriber::set_global_default(subscriber)?;
+    info!("Exporting traces to Jaeger at {}", jaeger_agent_endpoint);
+
+    Ok(())
+}
+
+/// Sends the spans which weren't exported yet
+pub fn shutdown_tracing() {
+    global::shutdown_tracer_provider();
+}
+
+/// Creates the span of an incoming gRPC request
+///
+/// The span continues the trace of the caller if the request carries a trace context.
+pub fn request_span<B>(request: &http::Request<B>) -> Span {
+    let span = tracing::info_span!(
+        "grpc_request",
+        method = request.uri().path(),
+        channel_id = tracing::field::Empty,
+    );
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+    span.set_parent(parent);
+
+    span
+}
+
+/// Attaches the user a request is about to its span
+pub fn record_channel_id(channel_id: &str) {
+    Span::current().record("channel_id", &channel_id);
+}